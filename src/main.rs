@@ -1,139 +1,2140 @@
 // File: src/main.rs
 
+use std::collections::HashMap;
 use std::env;
-use std::fs;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::path::Path;
-use std::process::Command;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Methods defined by the HTTP spec; anything else is answered with 501
+const STANDARD_METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, OPTIONS";
+// What each kind of resource answers, for Allow headers
+const FILE_METHODS: &str = "GET, HEAD, OPTIONS";
+const SCRIPT_METHODS: &str = "POST, OPTIONS";
+
+const USAGE: &str = "Usage: rustwebserver [OPTIONS] PORT ROOT_FOLDER
+       rustwebserver [OPTIONS] --file FILE PORT
+       rustwebserver [OPTIONS] --stdin PORT
+       rustwebserver --show-mime PATH
+
+Options:
+  --file FILE              Serve only FILE, at / and at its own name
+  --stdin                  Serve only the data piped on standard input, at /
+  --follow-symlinks        Serve symlinks whose target stays inside the root
+  --listen PORT            Also listen on PORT (repeatable, alias --port)
+  --ipv6                   Listen on [::] as well as 0.0.0.0
+  --no-cgi                 Never execute scripts; POSTs to scripts/ get 405
+  --no-tcp-nodelay         Leave Nagle's algorithm enabled on connections
+  --case-insensitive       Match file names ignoring case when there is no exact match
+  --sniff                  Guess the type of extensionless files from their content
+  --verbose-errors         Explain 5xx errors in the response body (development only)
+  --allow-tail             Serve the end of a file for ?tail=N (bytes) or ?tail=Nl (lines)
+  --allow-content-type-override
+                           Serve files with the type given in ?content-type=TYPE
+  --base-path PREFIX       Serve everything under the URL prefix PREFIX
+  --root-redirect URL      Redirect requests for / to URL
+  --root-redirect-status CODE
+                           Status for --root-redirect: 301, 302 (default), 303, 307 or 308
+  --exclude GLOB           Never serve matching paths (repeatable, e.g. \"*.tmp\")
+  --etag-mode MODE         mtime-size (default), inode or hash
+  --cache-rule \"EXT,...=VALUE\"
+                           Cache-Control for files with these extensions (repeatable, * = default)
+  --header \"NAME: VALUE\"   Add a header to every response (repeatable)
+  --hotlink-protect EXTS   Refuse these comma-separated file types to pages on other sites (403)
+  --allow-referer HOST     Also accept pages on HOST for protected files (repeatable, e.g. \"*.example.com\")
+  --hotlink-deny-empty     Refuse protected files to requests without a Referer
+  --svg-policy [DIR=]MODE  Serve SVGs (under DIR) as inline (default), csp, attachment or text (repeatable)
+  --hit-count              Send X-Hit-Count on files and serve counts at /__stats
+  --admin-allow CIDR       Let this network reach /__stats (repeatable, default loopback only)
+  --max-uri-length N       Answer 414 to request targets longer than N bytes (default 8192, at most 1048576, 0 = 1048576)
+  --max-connections N      Answer 503 beyond N simultaneous connections (0 = unlimited)
+  --max-open-files N       Files served at once before waiting, then 503 (default: half the fd limit, 0 = unlimited)
+  --request-timeout SECS   Limit the time spent on one request (default 60, 0 = off)
+  --maintenance PAGE       Answer every request with 503 and PAGE
+  --maintenance-allow PATH Keep serving PATH during maintenance (repeatable, trailing * allowed)
+  --maintenance-bypass CIDR
+                           Keep serving this network during maintenance (repeatable)
+  --request-id             Tag each request with an X-Request-Id, reusing the client's
+  --log-format FORMAT      Access log line, e.g. \"%h %t \\\"%r\\\" %>s %b %D\" (%h %t %r %m %U %q %s %>s %b %B %D %T %L %{Header}i %%)
+  --slow-request-threshold TIME
+                           Warn about requests slower than TIME (e.g. 1s, 500ms)
+  --self-test              Request a file from the root at startup and report the result
+  --check, --dry-run       Validate the configuration and exit";
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 60;
+const ENOEXEC: i32 = 8;
+const STATS_PATH: &str = "__stats";
+const RETRY_AFTER_SECS: &str = "5";
+// Planned downtime is expected to last longer than an overload
+const MAINTENANCE_RETRY_AFTER_SECS: &str = "300";
+const DEFAULT_MAX_OPEN_FILES: usize = 256;
+const FILE_SLOT_WAIT_SECS: u64 = 2;
+const DEFAULT_MAX_URI_LENGTH: usize = 8192;
+// Largest --max-uri-length, and the limit that applies with 0
+const MAX_URI_LENGTH_LIMIT: usize = 1024 * 1024;
+// Room for the request headers on top of the longest allowed target
+const HEADER_ROOM: usize = 8192;
+// How long the rest of the headers may take once a request has started
+const HEADER_READ_TIMEOUT_SECS: u64 = 5;
+// How much of a refused request's target goes into the log
+const MAX_LOGGED_TARGET: usize = 256;
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+// Per-path response headers, read from the root folder at startup
+const HEADERS_FILE: &str = "_headers";
+// "from to [status]" rules, read from the root folder at startup
+const REDIRECTS_FILE: &str = "_redirects";
+
+// A path pattern from the _headers file and the headers it adds
+type HeaderRule = (String, Vec<(String, String)>);
+
+struct RedirectRule {
+    // Request path without its leading slash; a trailing '*' matches the rest
+    from: String,
+    // Location value, where :splat stands for what the '*' matched
+    to: String,
+    status_code: u16,
+    status_text: &'static str,
+}
+
+struct Config {
+    // The PORT argument first, then any --listen ports
+    ports: Vec<u16>,
+    root_folder: PathBuf,
+    // When off, symlinked files and directories are treated as missing
+    follow_symlinks: bool,
+    // Listen on [::] as well as 0.0.0.0
+    ipv6: bool,
+    // Fall back to matching path components ignoring case
+    case_insensitive: bool,
+    // Run scripts under scripts/ on POST; off with --no-cgi
+    cgi: bool,
+    // Disable Nagle's algorithm on accepted sockets
+    tcp_nodelay: bool,
+    // Honour the non-standard ?tail= query on files
+    allow_tail: bool,
+    // Let ?content-type= replace the type derived from the extension
+    allow_content_type_override: bool,
+    // Guess the type of extensionless files from their first bytes
+    sniff: bool,
+    // Put the cause of 5xx errors in the response body; for development
+    verbose_errors: bool,
+    // URL prefix stripped before resolving against the root, without
+    // surrounding slashes
+    base_path: Option<String>,
+    // Probe the root through the request handler after binding
+    self_test: bool,
+    // Longer request targets get 414; None disables the check
+    max_uri_length: Option<usize>,
+    // Upper bound on handling a single request; None disables it
+    request_timeout: Option<Duration>,
+    // Requests taking longer are logged with a warning; None is off
+    slow_request_threshold: Option<Duration>,
+    // Glob patterns for paths that are never served
+    exclude: Vec<String>,
+    // How file ETags are derived
+    etag_mode: EtagMode,
+    // Cache-Control values by file extension, "*" being the fallback
+    cache_rules: Vec<(Vec<String>, String)>,
+    // Extensions refused to pages on other sites; empty disables the check
+    hotlink_extensions: Vec<String>,
+    // Referer hosts besides the request's own Host, as globs
+    allowed_referers: Vec<String>,
+    // Whether protected files are served without any Referer
+    hotlink_allow_empty: bool,
+    // How SVGs are served under a folder, without surrounding slashes
+    // ("" for the whole root); the longest matching folder wins
+    svg_policies: Vec<(String, SvgPolicy)>,
+    // Added to every response unless the handler set the same header
+    extra_headers: Vec<(String, String)>,
+    // Rules from the _headers file, in file order
+    path_headers: Vec<HeaderRule>,
+    // Rules from the _redirects file, first match wins
+    redirects: Vec<RedirectRule>,
+    // Networks allowed to reach admin endpoints such as /__stats, as
+    // (address, prefix length); loopback only unless --admin-allow is given
+    admin_networks: Vec<(IpAddr, u8)>,
+    // --maintenance: every request gets 503 with this page, except the
+    // bypass paths and networks
+    maintenance: Option<Maintenance>,
+    // Per-file serve counts, only kept with --hit-count
+    hit_counts: Option<Mutex<HashMap<String, u64>>>,
+    // --file / --stdin: serve only this content, no root folder or scripts
+    single_file: Option<SingleFile>,
+    // Limits files open at once in handle_get; None is unlimited
+    file_slots: Option<FileSlots>,
+    // Apache-style access log template; None keeps the built-in line
+    log_format: Option<Vec<LogField>>,
+    // Connections beyond this are answered with 503 straight away
+    max_connections: Option<usize>,
+    active_connections: AtomicUsize,
+    // --request-id: tag requests with an X-Request-Id
+    request_ids: bool,
+    // Numbers the generated request IDs
+    request_counter: AtomicU64,
+}
+
+struct Maintenance {
+    page: Vec<u8>,
+    content_type: &'static str,
+    // Path patterns served as usual, e.g. a health check
+    allowed_paths: Vec<String>,
+    bypass_networks: Vec<(IpAddr, u8)>,
+}
+
+enum SingleFile {
+    Path(PathBuf),
+    Stdin(Vec<u8>),
+}
 
 fn main() {
     
     let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        eprintln!("Usage: rustwebserver PORT ROOT_FOLDER");
+    if args.len() == 3 && args[1] == "--show-mime" {
+        show_mime(&args[2]);
         return;
     }
-    let port = &args[1];
-    let root_folder = &args[2];
 
-    // existance du root file
-    if !Path::new(root_folder).exists() {
-        eprintln!("Error: The specified root folder does not exist: {}", root_folder);
+    let mut check = false;
+    let mut remaining = Vec::new();
+    for arg in &args[1..] {
+        if arg == "--check" || arg == "--dry-run" {
+            check = true;
+        } else {
+            remaining.push(arg.clone());
+        }
+    }
+
+    let config = match load_config(&remaining) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if check {
+        print_config_summary(&config);
         return;
     }
 
     //  startup log
-    println!("Root folder: {}", fs::canonicalize(root_folder).unwrap().display());
-    println!("Server listening on 0.0.0.0:{}", port);
-
-    // Start TCP listener
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let root_folder = root_folder.clone();
+    match &config.single_file {
+        Some(SingleFile::Path(file_path)) => println!("Serving single file: {}", file_path.display()),
+        Some(SingleFile::Stdin(contents)) => println!("Serving {} bytes from standard input", contents.len()),
+        None => println!("Root folder: {}", config.root_folder.display()),
+    }
+
+    let config = Arc::new(config);
+
+    // Start TCP listeners, one accept loop per address
+    let mut listeners = Vec::new();
+    for &port in &config.ports {
+        listeners.extend(bind_port(&config, port));
+    }
+
+    if config.self_test {
+        run_self_test(&config);
+    }
+
+    let handles: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            let config = Arc::clone(&config);
+            thread::spawn(move || serve(listener, config))
+        })
+        .collect();
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
+
+// Pushes a request through handle_request the same way a client would, so
+// configuration mistakes show up at boot. Directories are never served, so
+// the probe targets index.html or else the first file in the root.
+fn run_self_test(config: &Config) {
+    if config.single_file.is_some() {
+        report_self_test(config, String::new());
+        return;
+    }
+
+    let mut files = match servable_root_files(config) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("WARNING: Self-test: root folder is unreadable: {}", e);
+            return;
+        }
+    };
+    if files.is_empty() {
+        eprintln!("WARNING: Self-test: root folder contains no files to serve");
+        return;
+    }
+    files.sort();
+    let path = if files.iter().any(|name| name == "index.html") {
+        "index.html".to_string()
+    } else {
+        files.swap_remove(0)
+    };
+
+    report_self_test(config, path);
+}
+
+// Files directly in the root that a GET could return, leaving out the
+// control files and --exclude matches, which are answered with 404
+fn servable_root_files(config: &Config) -> std::io::Result<Vec<String>> {
+    Ok(fs::read_dir(&config.root_folder)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && !is_control_file(config, path) && !is_excluded(config, path))
+        .filter_map(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()))
+        .collect())
+}
+
+fn report_self_test(config: &Config, path: String) {
+    let request = Request {
+        method: "GET".to_string(),
+        raw: format!("GET /{} HTTP/1.1\r\n\r\n", path),
+        path,
+        query: String::new(),
+        client_address: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        id: None,
+    };
+    let response = handle_request(config, &request);
+    if response.status_code == 200 {
+        println!("Self-test: GET /{} -> 200 OK", request.path);
+    } else {
+        eprintln!(
+            "WARNING: Self-test: GET /{} -> {} {}",
+            request.path, response.status_code, response.status_text
+        );
+    }
+}
+
+fn bind_port(config: &Config, port: u16) -> Vec<TcpListener> {
+    if !config.ipv6 {
+        return vec![bind_listener(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))];
+    }
+
+    let mut listeners = vec![bind_listener(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))];
+    // Where the IPv6 socket is dual-stack it already accepts IPv4, so the
+    // IPv4 bind is expected to fail with AddrInUse
+    let ipv4_address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    match TcpListener::bind(ipv4_address) {
+        Ok(listener) => {
+            println!("Server listening on {}", ipv4_address);
+            listeners.push(listener);
+        }
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            println!("IPv4 connections on port {} are served by the dual-stack IPv6 listener", port);
+        }
+        Err(e) => eprintln!("Warning: Cannot listen on {}: {}", ipv4_address, e),
+    }
+    listeners
+}
+
+fn bind_listener(address: SocketAddr) -> TcpListener {
+    match TcpListener::bind(address) {
+        Ok(listener) => {
+            println!("Server listening on {}", address);
+            listener
+        }
+        Err(e) => {
+            match e.kind() {
+                ErrorKind::AddrInUse => eprintln!("Error: Port {} is already in use", address.port()),
+                ErrorKind::PermissionDenied => eprintln!(
+                    "Error: Permission denied binding port {} (ports below 1024 usually need root)",
+                    address.port()
+                ),
+                _ => eprintln!("Error: Cannot listen on {}: {}", address, e),
+            }
+            process::exit(1);
+        }
+    }
+}
+
+fn serve(listener: TcpListener, config: Arc<Config>) {
+    loop {
+        let (stream, mut client_address) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        // Report IPv4 clients of a dual-stack socket with their plain address
+        if let IpAddr::V6(ip) = client_address.ip() {
+            if let Some(ipv4) = ip.to_ipv4_mapped() {
+                client_address.set_ip(IpAddr::V4(ipv4));
+            }
+        }
+        if config.tcp_nodelay {
+            if let Err(e) = stream.set_nodelay(true) {
+                eprintln!("Failed to set TCP_NODELAY for {}: {}", client_address, e);
+            }
+        }
+        // Counted here rather than in the thread so a burst of accepts is
+        // seen immediately; the guard gives the slot back however the
+        // connection ends
+        let active = config.active_connections.fetch_add(1, Ordering::SeqCst) + 1;
+        let guard = ConnectionGuard(Arc::clone(&config));
+        let config = Arc::clone(&config);
         thread::spawn(move || {
-            handle_connection(stream, &root_folder);
+            let _guard = guard;
+            match config.max_connections {
+                Some(max_connections) if active > max_connections => {
+                    shed_connection(stream, &config, client_address)
+                }
+                _ => handle_connection(stream, client_address, &config),
+            }
         });
     }
 }
 
-fn handle_connection(mut stream: TcpStream, root_folder: &str) {
+struct ConnectionGuard(Arc<Config>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Answers 503 without handling the request. The request is read first (with
+// a short timeout) so closing the socket doesn't reset it before the client
+// sees the response.
+fn shed_connection(mut stream: TcpStream, config: &Config, client_address: SocketAddr) {
+    println!("Too many connections, rejecting {}", client_address);
     let mut buffer = [0; 8192];
-    if let Err(e) = stream.read(&mut buffer) {
-        eprintln!("Failed to read from stream: {}", e);
-        return;
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+    let bytes_read = stream.read(&mut buffer).unwrap_or(0);
+    let raw = String::from_utf8_lossy(&buffer[..bytes_read]);
+    let detail = format!("{} connections already open", config.active_connections.load(Ordering::SeqCst));
+    let response = error_response_with_detail(config, &raw, 503, "Service Unavailable", &detail)
+        .with_header("Retry-After", RETRY_AFTER_SECS);
+    send_response(&mut stream, config, &response, true);
+}
+
+// Prints the type handle_get would send for a path, and what --sniff would
+// change when the extension lookup falls back to the default
+fn show_mime(path: &str) {
+    let content_type = get_content_type(path);
+    let sniffed = if content_type == DEFAULT_CONTENT_TYPE {
+        fs::read(path).ok().and_then(|contents| sniff_content_type(&contents))
+    } else {
+        None
+    };
+    match sniffed {
+        Some(sniffed) => println!("{}: {} ({} with --sniff)", path, content_type, sniffed),
+        None => println!("{}: {}", path, content_type),
+    }
+}
+
+fn load_config(args: &[String]) -> Result<Config, String> {
+    let mut positional = Vec::new();
+    let mut follow_symlinks = false;
+    let mut ipv6 = false;
+    let mut case_insensitive = false;
+    let mut cgi = true;
+    let mut tcp_nodelay = true;
+    let mut allow_tail = false;
+    let mut allow_content_type_override = false;
+    let mut sniff = false;
+    let mut verbose_errors = false;
+    let mut self_test = false;
+    let mut hit_counts = None;
+    let mut base_path = None;
+    let mut root_redirect = None;
+    let mut root_redirect_status = 302;
+    let mut file_option = None;
+    let mut read_stdin = false;
+    let mut max_connections = None;
+    let mut log_format = None;
+    let mut request_ids = false;
+    let mut admin_networks = Vec::new();
+    let mut maintenance_page = None;
+    let mut maintenance_paths = Vec::new();
+    let mut maintenance_networks = Vec::new();
+    let mut max_open_files = Some(default_max_open_files());
+    let mut etag_mode = EtagMode::MtimeSize;
+    let mut exclude = Vec::new();
+    let mut ports = Vec::new();
+    let mut extra_headers = Vec::new();
+    let mut cache_rules = Vec::new();
+    let mut svg_policies = Vec::new();
+    let mut hotlink_extensions = Vec::new();
+    let mut allowed_referers = Vec::new();
+    let mut hotlink_allow_empty = true;
+    let mut max_uri_length = Some(DEFAULT_MAX_URI_LENGTH);
+    let mut request_timeout = Some(Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS));
+    let mut slow_request_threshold = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--follow-symlinks" => follow_symlinks = true,
+            "--ipv6" => ipv6 = true,
+            "--case-insensitive" => case_insensitive = true,
+            "--no-cgi" => cgi = false,
+            "--no-tcp-nodelay" => tcp_nodelay = false,
+            "--sniff" => sniff = true,
+            "--verbose-errors" => verbose_errors = true,
+            "--allow-tail" => allow_tail = true,
+            "--allow-content-type-override" => allow_content_type_override = true,
+            "--self-test" => self_test = true,
+            "--hit-count" => hit_counts = Some(Mutex::new(HashMap::new())),
+            "--file" => file_option = Some(option_value(&mut args, arg)?),
+            "--stdin" => read_stdin = true,
+            "--base-path" => {
+                let value = option_value(&mut args, arg)?.trim_matches('/');
+                base_path = if value.is_empty() { None } else { Some(value.to_string()) };
+            }
+            "--header" => extra_headers.push(parse_header_option(option_value(&mut args, arg)?)?),
+            "--cache-rule" => cache_rules.push(parse_cache_rule(option_value(&mut args, arg)?)?),
+            "--hotlink-protect" => {
+                let value = option_value(&mut args, arg)?;
+                hotlink_extensions.extend(
+                    value
+                        .split(',')
+                        .map(|extension| extension.trim().trim_start_matches('.').to_ascii_lowercase())
+                        .filter(|extension| !extension.is_empty()),
+                );
+            }
+            "--allow-referer" => allowed_referers.push(option_value(&mut args, arg)?.to_ascii_lowercase()),
+            "--hotlink-deny-empty" => hotlink_allow_empty = false,
+            "--svg-policy" => svg_policies.push(parse_svg_policy(option_value(&mut args, arg)?)?),
+            "--listen" | "--port" => ports.push(parse_port(option_value(&mut args, arg)?)?),
+            "--exclude" => exclude.push(option_value(&mut args, arg)?.trim_matches('/').to_string()),
+            "--etag-mode" => {
+                etag_mode = match option_value(&mut args, arg)?.as_str() {
+                    "mtime-size" => EtagMode::MtimeSize,
+                    "inode" => EtagMode::Inode,
+                    "hash" => EtagMode::Hash,
+                    value => return Err(format!("Invalid value for {}: {}", arg, value)),
+                };
+            }
+            "--root-redirect" => {
+                let value = option_value(&mut args, arg)?;
+                if value.is_empty() || value.chars().any(|c| c.is_control() || c == ' ') {
+                    return Err(format!("Invalid value for {}: {}", arg, value));
+                }
+                root_redirect = Some(value.to_string());
+            }
+            "--root-redirect-status" => {
+                let value = option_value(&mut args, arg)?;
+                root_redirect_status = value
+                    .parse()
+                    .ok()
+                    .filter(|code| redirect_status_text(*code).is_some())
+                    .ok_or_else(|| format!("Invalid value for {}: {}", arg, value))?;
+            }
+            "--admin-allow" => admin_networks.push(parse_network(option_value(&mut args, arg)?)?),
+            "--maintenance" => maintenance_page = Some(option_value(&mut args, arg)?),
+            "--maintenance-allow" => {
+                let value = option_value(&mut args, arg)?;
+                maintenance_paths.push(value.trim_start_matches('/').to_string());
+            }
+            "--maintenance-bypass" => maintenance_networks.push(parse_network(option_value(&mut args, arg)?)?),
+            "--request-id" => request_ids = true,
+            "--log-format" => log_format = Some(parse_log_format(option_value(&mut args, arg)?)?),
+            "--max-uri-length" => {
+                let value = option_value(&mut args, arg)?;
+                let limit = value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|limit| *limit <= MAX_URI_LENGTH_LIMIT)
+                    .ok_or_else(|| format!("Invalid value for {}: {}", arg, value))?;
+                // 0 lifts the check on targets; the request line must still
+                // fit in the largest read buffer
+                max_uri_length = if limit == 0 { None } else { Some(limit) };
+            }
+            "--max-connections" => {
+                let value = option_value(&mut args, arg)?;
+                let limit = value
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid value for {}: {}", arg, value))?;
+                // 0 means unlimited
+                max_connections = if limit == 0 { None } else { Some(limit) };
+            }
+            "--max-open-files" => {
+                let value = option_value(&mut args, arg)?;
+                let limit = value
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid value for {}: {}", arg, value))?;
+                // 0 means unlimited
+                max_open_files = if limit == 0 { None } else { Some(limit) };
+            }
+            "--request-timeout" => {
+                let value = option_value(&mut args, arg)?;
+                let secs = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid value for {}: {}", arg, value))?;
+                // 0 turns the timeout off
+                request_timeout = if secs == 0 { None } else { Some(Duration::from_secs(secs)) };
+            }
+            "--slow-request-threshold" => {
+                let value = option_value(&mut args, arg)?;
+                slow_request_threshold = Some(
+                    parse_duration(value).ok_or_else(|| format!("Invalid value for {}: {}", arg, value))?,
+                );
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}\n{}", arg, USAGE)),
+            _ => positional.push(arg),
+        }
+    }
+    if file_option.is_some() && read_stdin {
+        return Err("--file and --stdin can't be combined".to_string());
+    }
+    let single_file_mode = file_option.is_some() || read_stdin;
+    if single_file_mode && positional.len() != 1 {
+        return Err(format!("Expected only PORT with --file or --stdin\n{}", USAGE));
+    }
+    if !single_file_mode && positional.len() != 2 {
+        return Err(format!("Expected PORT and ROOT_FOLDER\n{}", USAGE));
+    }
+    ports.insert(0, parse_port(positional[0])?);
+    let mut unique_ports = Vec::new();
+    for port in ports {
+        if !unique_ports.contains(&port) {
+            unique_ports.push(port);
+        }
+    }
+    let ports = unique_ports;
+
+    // In single-file mode the root folder is only used for logging: the
+    // file's own folder, or the working directory for --stdin
+    let mut single_file = None;
+    let root_folder = if let Some(file) = file_option {
+        let file = expand_path(file).map_err(|e| format!("Cannot expand file {}: {}", file, e))?;
+        let file = fs::canonicalize(&file).map_err(|e| format!("Cannot access file {}: {}", file, e))?;
+        if !file.is_file() {
+            return Err(format!("Not a regular file: {}", file.display()));
+        }
+        let parent = file.parent().unwrap_or(Path::new("/")).to_string_lossy().into_owned();
+        single_file = Some(SingleFile::Path(file));
+        parent
+    } else if read_stdin {
+        let mut contents = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut contents)
+            .map_err(|e| format!("Cannot read standard input: {}", e))?;
+        single_file = Some(SingleFile::Stdin(contents));
+        ".".to_string()
+    } else {
+        expand_path(positional[1])
+            .map_err(|e| format!("Cannot expand root folder {}: {}", positional[1], e))?
+    };
+
+    // existance du root file
+    if !Path::new(&root_folder).exists() {
+        return Err(format!("The specified root folder does not exist: {}", root_folder));
+    }
+    // The folder can exist and still be unreachable, e.g. through a parent
+    // directory without search permission
+    let root_folder = fs::canonicalize(&root_folder)
+        .map_err(|e| format!("Cannot access root folder '{}': {}", root_folder, e))?;
+    if let Err(e) = fs::read_dir(&root_folder) {
+        return Err(format!("Cannot read root folder {}: {}", root_folder.display(), e));
+    }
+    let (path_headers, mut redirects) = if single_file.is_none() {
+        (
+            load_headers_file(&root_folder.join(HEADERS_FILE))?,
+            load_redirects_file(&root_folder.join(REDIRECTS_FILE))?,
+        )
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    // Ahead of the _redirects rules, so it wins for exactly "/"
+    if let Some(to) = root_redirect {
+        redirects.insert(
+            0,
+            RedirectRule {
+                from: String::new(),
+                to,
+                status_code: root_redirect_status,
+                status_text: redirect_status_text(root_redirect_status).unwrap_or("Found"),
+            },
+        );
+    }
+
+    let maintenance = match maintenance_page {
+        Some(page) => {
+            let page_path = expand_path(page).map_err(|e| format!("Cannot expand maintenance page {}: {}", page, e))?;
+            let contents =
+                fs::read(&page_path).map_err(|e| format!("Cannot read maintenance page {}: {}", page_path, e))?;
+            Some(Maintenance {
+                page: contents,
+                content_type: get_content_type(&page_path),
+                allowed_paths: maintenance_paths,
+                bypass_networks: maintenance_networks,
+            })
+        }
+        None if !maintenance_paths.is_empty() || !maintenance_networks.is_empty() => {
+            return Err("--maintenance-allow and --maintenance-bypass need --maintenance".to_string());
+        }
+        None => None,
+    };
+
+    if admin_networks.is_empty() {
+        admin_networks = vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), 8), (IpAddr::V6(Ipv6Addr::LOCALHOST), 128)];
+    }
+
+    Ok(Config {
+        ports,
+        root_folder,
+        follow_symlinks,
+        ipv6,
+        case_insensitive,
+        cgi,
+        tcp_nodelay,
+        allow_tail,
+        allow_content_type_override,
+        sniff,
+        verbose_errors,
+        base_path,
+        self_test,
+        max_uri_length,
+        request_timeout,
+        slow_request_threshold,
+        exclude,
+        etag_mode,
+        cache_rules,
+        svg_policies,
+        hotlink_extensions,
+        allowed_referers,
+        hotlink_allow_empty,
+        extra_headers,
+        path_headers,
+        redirects,
+        admin_networks,
+        maintenance,
+        hit_counts,
+        single_file,
+        file_slots: max_open_files.map(FileSlots::new),
+        max_connections,
+        log_format,
+        active_connections: AtomicUsize::new(0),
+        request_ids,
+        request_counter: AtomicU64::new(0),
+    })
+}
+
+fn parse_port(value: &str) -> Result<u16, String> {
+    value.parse::<u16>().map_err(|_| format!("Invalid port: {}", value))
+}
+
+// Parses a --header "Name: Value" option. The framing headers are written by
+// send_response and can't be overridden.
+fn parse_header_option(option: &str) -> Result<(String, String), String> {
+    let (name, value) = option
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid header, expected \"Name: Value\": {}", option))?;
+    let (name, value) = (name.trim(), value.trim());
+    if !is_token(name) {
+        return Err(format!("Invalid header name: {}", name));
+    }
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        return Err(format!("Invalid value for header {}", name));
+    }
+    if ["Date", "Content-Length", "Transfer-Encoding", "Connection"]
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(name))
+    {
+        return Err(format!("Header {} is set by the server and can't be overridden", name));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+// RFC 7230 token, as used for header names and media types
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+// "type/subtype", optionally followed by ";name=value" parameters
+fn is_media_type(value: &str) -> bool {
+    let mut parts = value.split(';');
+    let valid_type = match parts.next().unwrap_or_default().trim().split_once('/') {
+        Some((main_type, subtype)) => is_token(main_type) && is_token(subtype),
+        None => false,
+    };
+    valid_type
+        && parts.all(|parameter| match parameter.trim().split_once('=') {
+            Some((name, value)) => is_token(name) && is_token(value),
+            None => false,
+        })
+}
+
+// Parses --cache-rule "js,css=public,max-age=31536000"
+fn parse_cache_rule(option: &str) -> Result<(Vec<String>, String), String> {
+    let (extensions, value) = option
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid cache rule, expected \"EXT,...=VALUE\": {}", option))?;
+    let extensions: Vec<String> = extensions
+        .split(',')
+        .map(|extension| extension.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|extension| !extension.is_empty())
+        .collect();
+    let value = value.trim();
+    if extensions.is_empty() || value.is_empty() || value.chars().any(|c| c.is_control()) {
+        return Err(format!("Invalid cache rule: {}", option));
+    }
+    Ok((extensions, value.to_string()))
+}
+
+fn parse_svg_policy(option: &str) -> Result<(String, SvgPolicy), String> {
+    let (folder, mode) = option.split_once('=').unwrap_or(("", option));
+    let policy = match mode.trim() {
+        "inline" => SvgPolicy::Inline,
+        "csp" => SvgPolicy::Csp,
+        "attachment" => SvgPolicy::Attachment,
+        "text" => SvgPolicy::Text,
+        _ => return Err(format!("Invalid SVG policy, expected inline, csp, attachment or text: {}", option)),
+    };
+    Ok((folder.trim().trim_matches('/').to_string(), policy))
+}
+
+// The _headers format: an unindented line is a path pattern, and the
+// indented "Name: Value" lines below it are the headers for that pattern.
+// Blank lines and lines starting with '#' are ignored.
+fn load_headers_file(path: &Path) -> Result<Vec<HeaderRule>, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Cannot read {}: {}", path.display(), e)),
+    };
+    let mut rules: Vec<HeaderRule> = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let location = format!("{} line {}", path.display(), number + 1);
+        if !line.starts_with(char::is_whitespace) {
+            rules.push((trimmed.trim_start_matches('/').to_string(), Vec::new()));
+            continue;
+        }
+        let (_, headers) = rules
+            .last_mut()
+            .ok_or_else(|| format!("{}: header before any path pattern", location))?;
+        headers.push(parse_header_option(trimmed).map_err(|e| format!("{}: {}", location, e))?);
+    }
+    Ok(rules)
+}
+
+// One "from to [status]" rule per line, status defaulting to 301.
+// Blank lines and lines starting with '#' are ignored.
+fn load_redirects_file(path: &Path) -> Result<Vec<RedirectRule>, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Cannot read {}: {}", path.display(), e)),
+    };
+    let mut rules = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let location = format!("{} line {}", path.display(), number + 1);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (from, to, status) = match fields.as_slice() {
+            [from, to] => (*from, *to, "301"),
+            [from, to, status] => (*from, *to, *status),
+            _ => return Err(format!("{}: expected \"from to [status]\"", location)),
+        };
+        let (status_code, status_text) = match status.parse() {
+            Ok(code) => match redirect_status_text(code) {
+                Some(text) => (code, text),
+                None => return Err(format!("{}: invalid redirect status {}", location, status)),
+            },
+            Err(_) => return Err(format!("{}: invalid redirect status {}", location, status)),
+        };
+        if to.chars().any(|c| c.is_control()) {
+            return Err(format!("{}: invalid redirect target", location));
+        }
+        rules.push(RedirectRule {
+            from: from.trim_start_matches('/').to_string(),
+            to: to.to_string(),
+            status_code,
+            status_text,
+        });
+    }
+    Ok(rules)
+}
+
+fn redirect_status_text(status_code: u16) -> Option<&'static str> {
+    match status_code {
+        301 => Some("Moved Permanently"),
+        302 => Some("Found"),
+        303 => Some("See Other"),
+        307 => Some("Temporary Redirect"),
+        308 => Some("Permanent Redirect"),
+        _ => None,
+    }
+}
+
+// A trailing '*' matches the rest of the path, across slashes; otherwise
+// the pattern is a glob like --exclude uses
+fn path_pattern_matches(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) if path.starts_with(prefix) => true,
+        _ => glob_match(pattern.as_bytes(), path.as_bytes()),
+    }
+}
+
+// "ADDRESS/PREFIX", or a bare address for that single host
+fn parse_network(option: &str) -> Result<(IpAddr, u8), String> {
+    let (address, prefix_len) = option.split_once('/').unwrap_or((option, ""));
+    let address: IpAddr = address
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid network address: {}", option))?;
+    let max_len = if address.is_ipv4() { 32 } else { 128 };
+    let prefix_len = if prefix_len.is_empty() {
+        max_len
+    } else {
+        match prefix_len.trim().parse::<u8>() {
+            Ok(len) if len <= max_len => len,
+            _ => return Err(format!("Invalid network prefix length: {}", option)),
+        }
+    };
+    Ok((address, prefix_len))
+}
+
+fn network_contains(network: IpAddr, prefix_len: u8, address: IpAddr) -> bool {
+    let (network, address, bits) = match (network, address) {
+        (IpAddr::V4(network), IpAddr::V4(address)) => (u32::from(network) as u128, u32::from(address) as u128, 32),
+        (IpAddr::V6(network), IpAddr::V6(address)) => (u128::from(network), u128::from(address), 128),
+        _ => return false,
+    };
+    let shift = bits - prefix_len as u32;
+    shift >= bits || network >> shift == address >> shift
+}
+
+fn in_networks(networks: &[(IpAddr, u8)], address: IpAddr) -> bool {
+    networks
+        .iter()
+        .any(|(network, prefix_len)| network_contains(*network, *prefix_len, address))
+}
+
+// Operational endpoints stay private even when listening on 0.0.0.0
+fn admin_only(config: &Config, request: &Request, handler: fn(&Config) -> Response) -> Response {
+    if in_networks(&config.admin_networks, request.client_address.ip()) {
+        handler(config)
+    } else {
+        println!("Admin path refused to {}: {}", request.client_address, request.path);
+        error_response(403, "Forbidden")
+    }
+}
+
+// "1500ms", "2s" or a bare number of seconds
+fn parse_duration(value: &str) -> Option<Duration> {
+    if let Some(millis) = value.strip_suffix("ms") {
+        return millis.parse().ok().map(Duration::from_millis);
+    }
+    let secs = value.strip_suffix('s').unwrap_or(value);
+    secs.parse().ok().map(Duration::from_secs)
+}
+
+fn option_value<'a>(args: &mut std::slice::Iter<'a, String>, name: &str) -> Result<&'a String, String> {
+    args.next().ok_or_else(|| format!("Missing value for {}", name))
+}
+
+fn print_config_summary(config: &Config) {
+    let scripts_folder = config.root_folder.join("scripts");
+    println!("Configuration OK");
+    let ports: Vec<String> = config.ports.iter().map(|port| port.to_string()).collect();
+    println!("  Ports: {}", ports.join(", "));
+    match &config.single_file {
+        Some(SingleFile::Path(file_path)) => println!("  Single file: {}", file_path.display()),
+        Some(SingleFile::Stdin(contents)) => println!("  Standard input: {} bytes", contents.len()),
+        None => {
+            println!("  Root folder: {}", config.root_folder.display());
+            if scripts_folder.is_dir() {
+                println!("  Scripts folder: {}", scripts_folder.display());
+            } else {
+                println!("  Scripts folder: {} (missing)", scripts_folder.display());
+            }
+        }
+    }
+    println!("  Follow symlinks: {}", if config.follow_symlinks { "on" } else { "off" });
+    println!("  IPv6: {}", if config.ipv6 { "on" } else { "off" });
+    println!("  Scripts: {}", if config.cgi { "enabled" } else { "disabled" });
+    println!("  TCP_NODELAY: {}", if config.tcp_nodelay { "on" } else { "off" });
+    println!("  Case-insensitive paths: {}", if config.case_insensitive { "on" } else { "off" });
+    println!("  MIME sniffing: {}", if config.sniff { "on" } else { "off" });
+    println!("  Verbose errors: {}", if config.verbose_errors { "on" } else { "off" });
+    println!("  Request IDs: {}", if config.request_ids { "on" } else { "off" });
+    println!("  Tail queries: {}", if config.allow_tail { "on" } else { "off" });
+    println!(
+        "  Content-type override: {}",
+        if config.allow_content_type_override { "on" } else { "off" }
+    );
+    println!("  Hit counting: {}", if config.hit_counts.is_some() { "on" } else { "off" });
+    for (address, prefix_len) in &config.admin_networks {
+        println!("  Admin access: {}/{}", address, prefix_len);
+    }
+    if let Some(maintenance) = &config.maintenance {
+        println!("  Maintenance mode: on ({} byte page)", maintenance.page.len());
+        for pattern in &maintenance.allowed_paths {
+            println!("  Maintenance allowed path: /{}", pattern);
+        }
+        for (address, prefix_len) in &maintenance.bypass_networks {
+            println!("  Maintenance bypass: {}/{}", address, prefix_len);
+        }
+    }
+    if let Some(base_path) = &config.base_path {
+        println!("  Base path: /{}", base_path);
+    }
+    let etag_mode = match config.etag_mode {
+        EtagMode::MtimeSize => "mtime-size",
+        EtagMode::Inode => "inode",
+        EtagMode::Hash => "hash",
+    };
+    println!("  ETag mode: {}", etag_mode);
+    for pattern in &config.exclude {
+        println!("  Exclude: {}", pattern);
+    }
+    for (extensions, value) in &config.cache_rules {
+        println!("  Cache rule: {} -> {}", extensions.join(","), value);
+    }
+    if !config.hotlink_extensions.is_empty() {
+        println!("  Hotlink protection: {}", config.hotlink_extensions.join(","));
+        for referer in &config.allowed_referers {
+            println!("  Allowed referer: {}", referer);
+        }
+        println!(
+            "  Requests without Referer: {}",
+            if config.hotlink_allow_empty { "allowed" } else { "denied" }
+        );
+    }
+    for (folder, policy) in &config.svg_policies {
+        println!("  SVG policy: /{} -> {}", folder, policy.name());
+    }
+    for (name, value) in &config.extra_headers {
+        println!("  Header: {}: {}", name, value);
+    }
+    for (pattern, headers) in &config.path_headers {
+        for (name, value) in headers {
+            println!("  Header for /{}: {}: {}", pattern, name, value);
+        }
+    }
+    for rule in &config.redirects {
+        println!("  Redirect: /{} -> {} ({})", rule.from, rule.to, rule.status_code);
+    }
+    match config.max_uri_length {
+        Some(max_uri_length) => println!("  Max URI length: {}", max_uri_length),
+        None => println!("  Max URI length: {} (read buffer)", MAX_URI_LENGTH_LIMIT),
+    }
+    match config.max_connections {
+        Some(max_connections) => println!("  Max connections: {}", max_connections),
+        None => println!("  Max connections: unlimited"),
+    }
+    match &config.file_slots {
+        Some(file_slots) => println!(
+            "  Max open files: {}",
+            file_slots.available.lock().unwrap_or_else(|e| e.into_inner())
+        ),
+        None => println!("  Max open files: unlimited"),
+    }
+    match config.request_timeout {
+        Some(timeout) => println!("  Request timeout: {}s", timeout.as_secs()),
+        None => println!("  Request timeout: off"),
+    }
+    match config.slow_request_threshold {
+        Some(threshold) => println!("  Slow request threshold: {}ms", threshold.as_millis()),
+        None => println!("  Slow request threshold: off"),
+    }
+}
+
+// Expands a leading ~ to the home directory and $VAR / ${VAR} references
+// to their environment values.
+fn expand_path(path: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        let home = env::var("HOME").map_err(|_| "HOME is not set".to_string())?;
+        expanded.push_str(&home);
+        rest = &rest[1..];
     }
 
-    let request = String::from_utf8_lossy(&buffer[..]);
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+        let mut name = String::new();
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => return Err("unterminated ${...}".to_string()),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+        }
+        if name.is_empty() {
+            expanded.push('$');
+            continue;
+        }
+        let value = env::var(&name).map_err(|_| format!("environment variable {} is not set", name))?;
+        expanded.push_str(&value);
+    }
+    Ok(expanded)
+}
+
+#[derive(Clone)]
+struct Request {
+    method: String,
+    // Decoded request path without its leading slashes
+    path: String,
+    // Raw query string, without the '?'
+    query: String,
+    // Full request text as read from the socket
+    raw: String,
+    client_address: SocketAddr,
+    // X-Request-Id for this request, with --request-id
+    id: Option<String>,
+}
+
+fn handle_connection(mut stream: TcpStream, client_address: SocketAddr, config: &Arc<Config>) {
+    // load_config keeps the limit under MAX_URI_LENGTH_LIMIT, so the sum
+    // can't overflow
+    let max_uri_length = config.max_uri_length.unwrap_or(MAX_URI_LENGTH_LIMIT).min(MAX_URI_LENGTH_LIMIT);
+    let mut buffer = vec![0; max_uri_length + HEADER_ROOM];
+    // The request line and headers can arrive over several reads; stop once
+    // the blank line after the headers is in or the buffer is full. A client
+    // that stops short of it is served with what it sent.
+    let mut bytes_read = 0;
+    while bytes_read < buffer.len() {
+        match stream.read(&mut buffer[bytes_read..]) {
+            Ok(0) => break,
+            Ok(n) => {
+                // The blank line may straddle two reads
+                let from = bytes_read.saturating_sub(3);
+                bytes_read += n;
+                let received = &buffer[from..bytes_read];
+                if received.windows(4).any(|w| w == b"\r\n\r\n") || received.windows(2).any(|w| w == b"\n\n") {
+                    break;
+                }
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(HEADER_READ_TIMEOUT_SECS)));
+            }
+            Err(e) if bytes_read == 0 => {
+                eprintln!("Failed to read from stream: {}", e);
+                return;
+            }
+            Err(_) => break,
+        }
+    }
+
+    let _ = stream.set_read_timeout(None);
+
+    let started = Instant::now();
+    let raw = String::from_utf8_lossy(&buffer[..bytes_read]);
+    // A request line that doesn't fit in the buffer can only be a target
+    // that is too long; serving its truncated prefix would be wrong
+    let truncated = bytes_read == buffer.len() && !raw.contains('\n');
+    // Taken before parsing, so requests that are refused can be traced too
+    let id = if config.request_ids {
+        Some(request_id(config, &raw))
+    } else {
+        None
+    };
+    let parsed = if truncated {
+        println!("Rejecting request line longer than {} bytes", buffer.len());
+        Err((raw.split(' ').next(), error_response(414, "URI Too Long")))
+    } else {
+        parse_request(config, &raw, client_address, id.clone())
+    };
+    let (request, response) = match parsed {
+        Ok(request) => {
+            let response = match config.request_timeout {
+                Some(timeout) => handle_request_with_timeout(config, timeout, &request),
+                None => handle_request(config, &request),
+            };
+            (request, response)
+        }
+        Err((method, response)) => (unparsed_request(&raw, method, client_address, id), response),
+    };
+    let mut response = if accepts_json(&raw) {
+        with_json_error_body(response)
+    } else {
+        response
+    };
+    if let Some(id) = &request.id {
+        response = response.with_header("X-Request-Id", id);
+    }
+    // A HEAD never gets a body, not even on an error it caused
+    let include_body = request.method != "HEAD";
+    send_response(&mut stream, config, &response, include_body);
+    // Closing with the rest of the line unread would reset the connection,
+    // and the client could lose the 414 before reading it
+    if truncated {
+        let _ = stream.shutdown(Shutdown::Write);
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+        let _ = io::copy(&mut (&stream).take(MAX_URI_LENGTH_LIMIT as u64), &mut io::sink());
+    }
+
+    // Log the request and response
+    let elapsed = started.elapsed();
+    if config.slow_request_threshold.is_some_and(|threshold| elapsed > threshold) {
+        eprintln!(
+            "WARNING: Slow request: {} {} took {}ms",
+            request.method,
+            request.path,
+            elapsed.as_millis()
+        );
+    }
+    match &config.log_format {
+        Some(fields) => {
+            let bytes_sent = if include_body { response.body.len() } else { 0 };
+            let line = format_log_line(fields, &request, &response, bytes_sent, elapsed);
+            println!("{}", line);
+        }
+        None => match &request.id {
+            Some(id) => println!("{} {} -> {} [{}]", request.method, request.client_address, request.path, id),
+            None => println!("{} {} -> {}", request.method, request.client_address, request.path),
+        },
+    }
+}
+
+// Stands in for a request parse_request refused, so it is logged like any
+// other. The path is the raw target, cut short for oversized lines.
+fn unparsed_request(raw: &str, method: Option<&str>, client_address: SocketAddr, id: Option<String>) -> Request {
+    let target = raw.lines().next().unwrap_or_default().split_whitespace().nth(1).unwrap_or_default();
+    Request {
+        method: method.unwrap_or("-").to_string(),
+        path: target.trim_start_matches('/').chars().take(MAX_LOGGED_TARGET).collect(),
+        query: String::new(),
+        raw: raw.to_string(),
+        client_address,
+        id,
+    }
+}
+
+// Turns the raw request text into a Request, or into the error response
+// that should be sent instead when it can't be served, along with the
+// method when the request line got that far
+fn parse_request<'a>(
+    config: &Config,
+    request: &'a str,
+    client_address: SocketAddr,
+    id: Option<String>,
+) -> Result<Request, (Option<&'a str>, Response)> {
     let request_line = match request.lines().next() {
         Some(line) => line,
         None => {
             eprintln!("Failed to parse request line");
-            respond_with_error(&mut stream, 400, "Bad Request");
-            return;
+            return Err((None, error_response(400, "Bad Request")));
         }
     };
 
     let mut parts = request_line.split_whitespace();
-    let method = parts.next().unwrap();
-    let path = parts.next().unwrap();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => {
+            eprintln!("Failed to parse request line: {}", request_line);
+            return Err((None, error_response(400, "Bad Request")));
+        }
+    };
+
+    // HTTP/1.0 and 1.1 share the same framing here: every response carries a
+    // Content-Length and the connection is closed afterwards.
+    if let Some(version) = parts.next() {
+        if version != "HTTP/1.0" && version != "HTTP/1.1" {
+            println!("Unsupported HTTP version: {}", version);
+            return Err((Some(method), error_response(505, "HTTP Version Not Supported")));
+        }
+    }
+
+    // CONNECT carries an authority-form target (host:port) and asks for a
+    // tunnel, which a file server never provides
+    if method == "CONNECT" {
+        println!("Rejecting CONNECT to {}", target);
+        return Err((Some(method), error_response(405, "Method Not Allowed").with_header("Allow", ALLOWED_METHODS)));
+    }
+
+    if let Some(max_uri_length) = config.max_uri_length {
+        if target.len() > max_uri_length {
+            println!("Rejecting {}-byte request target", target.len());
+            return Err((Some(method), error_response(414, "URI Too Long")));
+        }
+    }
+
+    // "OPTIONS *" asks about the server as a whole
+    if method == "OPTIONS" && target == "*" {
+        return Err((Some(method), Response::new(204, "No Content", Vec::new()).with_header("Allow", ALLOWED_METHODS)));
+    }
+
+    // Only origin-form targets are served; "*" and absolute URIs are refused.
+    // "/" maps to an empty path, which resolves to the root folder itself.
+    if !target.starts_with('/') {
+        println!("Rejecting request target: {}", target);
+        return Err((Some(method), error_response(400, "Bad Request")));
+    }
+    // Clients shouldn't send a fragment, but if one slips through it must
+    // not end up in the file name; the query is kept apart from the path
+    let target = target.split('#').next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
     let path = path.trim_start_matches('/');
 
     println!("Received {} request for path: {}", method, path);
 
-    if method == "GET" {
-        handle_get(&mut stream, root_folder, path);
+    // An encoded separator would let one segment carry a directory boundary,
+    // so such requests are refused outright rather than decoded
+    if has_encoded_separator(path) {
+        println!("Rejecting encoded path separator in: {}", path);
+        return Err((Some(method), error_response(400, "Bad Request")));
+    }
+
+    // NUL and other control characters could truncate or confuse file names
+    let path = match percent_decode(path) {
+        Some(path) if !path.chars().any(|c| c.is_ascii_control()) => path,
+        _ => {
+            println!("Rejecting malformed path: {}", path.escape_debug());
+            return Err((Some(method), error_response(400, "Bad Request")));
+        }
+    };
+
+    // Sloppy URLs are served as if they were clean: "//docs/./a.html" is
+    // "docs/a.html". ".." is never resolved, even when it would stay inside
+    // the root.
+    let path = match normalize_path(&path) {
+        Some(path) => path,
+        None => {
+            println!("Rejecting parent directory segment in: {}", path);
+            return Err((Some(method), error_response(400, "Bad Request")));
+        }
+    };
+
+    // Behind --base-path only URLs under the prefix exist
+    let path = match &config.base_path {
+        Some(base_path) => match strip_base_path(&path, base_path) {
+            Some(stripped) => stripped.to_string(),
+            None => {
+                println!("Outside base path: {}", path);
+                return Err((Some(method), error_response(404, "Not Found")));
+            }
+        },
+        None => path,
+    };
+
+    Ok(Request {
+        method: method.to_string(),
+        path,
+        query: query.to_string(),
+        raw: request.to_string(),
+        client_address,
+        id,
+    })
+}
+
+enum LogField {
+    Literal(String),
+    // %h
+    ClientIp,
+    // %t
+    Time,
+    // %r
+    RequestLine,
+    // %m
+    Method,
+    // %U
+    Path,
+    // %q
+    Query,
+    // %s or %>s
+    Status,
+    // %b, "-" when nothing was sent
+    BytesClf,
+    // %B
+    Bytes,
+    // %D
+    Micros,
+    // %T
+    Seconds,
+    // %L, "-" without --request-id
+    RequestId,
+    // %{Name}i
+    Header(String),
+}
+
+// Unknown placeholders are refused at startup rather than logged verbatim
+fn parse_log_format(format: &str) -> Result<Vec<LogField>, String> {
+    let mut fields = Vec::new();
+    let mut literal = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        let field = match chars.next() {
+            Some('%') => {
+                literal.push('%');
+                continue;
+            }
+            Some('h') => LogField::ClientIp,
+            Some('t') => LogField::Time,
+            Some('r') => LogField::RequestLine,
+            Some('m') => LogField::Method,
+            Some('U') => LogField::Path,
+            Some('q') => LogField::Query,
+            Some('s') => LogField::Status,
+            Some('>') if chars.next() == Some('s') => LogField::Status,
+            Some('b') => LogField::BytesClf,
+            Some('B') => LogField::Bytes,
+            Some('D') => LogField::Micros,
+            Some('T') => LogField::Seconds,
+            Some('L') => LogField::RequestId,
+            Some('{') => {
+                let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                if !is_token(&name) || chars.next() != Some('i') {
+                    return Err(format!("Invalid log format placeholder %{{{}}}: {}", name, format));
+                }
+                LogField::Header(name)
+            }
+            Some(other) => return Err(format!("Unknown log format placeholder %{}: {}", other, format)),
+            None => return Err(format!("Log format ends with a lone %: {}", format)),
+        };
+        if !literal.is_empty() {
+            fields.push(LogField::Literal(std::mem::take(&mut literal)));
+        }
+        fields.push(field);
+    }
+    if !literal.is_empty() {
+        fields.push(LogField::Literal(literal));
+    }
+    Ok(fields)
+}
+
+fn format_log_line(
+    fields: &[LogField],
+    request: &Request,
+    response: &Response,
+    bytes_sent: usize,
+    elapsed: Duration,
+) -> String {
+    let mut line = String::new();
+    for field in fields {
+        match field {
+            LogField::Literal(text) => line.push_str(text),
+            LogField::ClientIp => line.push_str(&request.client_address.ip().to_string()),
+            LogField::Time => line.push_str(&log_date(SystemTime::now())),
+            LogField::RequestLine => line.push_str(request.raw.lines().next().unwrap_or_default()),
+            LogField::Method => line.push_str(&request.method),
+            LogField::Path => {
+                line.push('/');
+                line.push_str(&request.path);
+            }
+            LogField::Query if request.query.is_empty() => {}
+            LogField::Query => {
+                line.push('?');
+                line.push_str(&request.query);
+            }
+            LogField::Status => line.push_str(&response.status_code.to_string()),
+            LogField::BytesClf if bytes_sent == 0 => line.push('-'),
+            LogField::BytesClf | LogField::Bytes => line.push_str(&bytes_sent.to_string()),
+            LogField::Micros => line.push_str(&elapsed.as_micros().to_string()),
+            LogField::Seconds => line.push_str(&elapsed.as_secs().to_string()),
+            LogField::RequestId => line.push_str(request.id.as_deref().unwrap_or("-")),
+            LogField::Header(name) => line.push_str(header_value(&request.raw, name).unwrap_or("-")),
+        }
+    }
+    line
+}
+
+// The client's own X-Request-Id is kept when it is a sane token, so a
+// proxy's ID carries through; otherwise the time in seconds and a counter
+// make one that stays unique across restarts
+fn request_id(config: &Config, raw: &str) -> String {
+    if let Some(id) = header_value(raw, "X-Request-Id") {
+        if id.len() <= 128 && is_token(id) {
+            return id.to_string();
+        }
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let number = config.request_counter.fetch_add(1, Ordering::SeqCst);
+    format!("{:x}-{:x}", now, number)
+}
+
+// Looks up a request header by name, ignoring case
+fn header_value<'a>(raw: &'a str, name: &str) -> Option<&'a str> {
+    raw.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+// API clients that ask for JSON get their errors as JSON too
+fn accepts_json(raw: &str) -> bool {
+    header_value(raw, "Accept").is_some_and(|accept| {
+        accept.split(',').any(|range| {
+            let media_type = range.split(';').next().unwrap_or_default().trim();
+            media_type.eq_ignore_ascii_case("application/json")
+        })
+    })
+}
+
+// Gives a bodiless error response a {"error": ..., "status": ...} body.
+// Responses that already carry a body, such as a script's stderr, are kept.
+fn with_json_error_body(response: Response) -> Response {
+    if response.status_code < 400 || !response.body.is_empty() {
+        return response;
+    }
+    let body = format!(
+        "{{\"error\": \"{}\", \"status\": {}}}",
+        json_escape(response.status_text),
+        response.status_code
+    );
+    let mut response = Response { body: body.into_bytes(), ..response };
+    response.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Content-type"));
+    response.with_header("Content-type", "application/json")
+}
+
+fn handle_request(config: &Config, request: &Request) -> Response {
+    let method = request.method.as_str();
+    if let Some(maintenance) = &config.maintenance {
+        if !bypasses_maintenance(maintenance, request) {
+            return Response::new(503, "Service Unavailable", maintenance.page.clone())
+                .with_header("Content-type", maintenance.content_type)
+                .with_header("Retry-After", MAINTENANCE_RETRY_AFTER_SECS);
+        }
+    }
+    if let Some(single_file) = &config.single_file {
+        return handle_single_file(config, single_file, request);
+    }
+    // HEAD is answered like GET; the body is dropped in send_response
+    let is_read = method == "GET" || method == "HEAD";
+    if is_read {
+        if let Some(response) = redirect_for(config, &request.path) {
+            return response;
+        }
+    }
+    if is_read && request.path.starts_with("scripts/") {
+        // Scripts only answer POST: GET would hand out their source, and
+        // running one just to drop its output for HEAD could have side
+        // effects
+        method_not_allowed(config, request)
+    } else if is_read && request.path == STATS_PATH && config.hit_counts.is_some() {
+        admin_only(config, request, stats_response)
+    } else if is_read {
+        handle_get(config, request)
     } else if method == "POST" {
-        handle_post(&mut stream, root_folder, path, &request);
+        handle_post(config, request)
+    } else if method == "OPTIONS" {
+        handle_options(config, request)
+    } else if STANDARD_METHODS.contains(&method) {
+        method_not_allowed(config, request)
     } else {
-        respond_with_error(&mut stream, 405, "Method Not Allowed");
+        error_response(501, "Not Implemented")
     }
+}
 
-    // Log the request and response
-    if let Ok(client_address) = stream.peer_addr() {
-        println!("{} {} -> {}", method, client_address, path);
+fn handle_single_file(config: &Config, single_file: &SingleFile, request: &Request) -> Response {
+    if request.method == "OPTIONS" {
+        return Response::new(204, "No Content", Vec::new()).with_header("Allow", FILE_METHODS);
+    }
+    if request.method != "GET" && request.method != "HEAD" {
+        return if STANDARD_METHODS.contains(&request.method.as_str()) {
+            error_response(405, "Method Not Allowed").with_header("Allow", FILE_METHODS)
+        } else {
+            error_response(501, "Not Implemented")
+        };
+    }
+
+    match single_file {
+        SingleFile::Path(file_path) => {
+            let file_name = file_path.file_name().map(|name| name.to_string_lossy());
+            if !request.path.is_empty() && file_name.as_deref() != Some(request.path.as_str()) {
+                return error_response(404, "Not Found");
+            }
+            match fs::read(file_path) {
+                Ok(contents) => {
+                    let content_type = get_content_type(&file_path.to_string_lossy());
+                    Response::new(200, "OK", contents).with_header("Content-type", content_type)
+                }
+                Err(e) => {
+                    println!("Forbidden: Cannot read file: {}, error: {}", file_path.display(), e);
+                    error_response(403, "Forbidden")
+                }
+            }
+        }
+        SingleFile::Stdin(contents) => {
+            if !request.path.is_empty() {
+                return error_response(404, "Not Found");
+            }
+            let content_type = match sniff_content_type(contents) {
+                Some(content_type) if config.sniff => content_type,
+                _ => DEFAULT_CONTENT_TYPE,
+            };
+            Response::new(200, "OK", contents.clone()).with_header("Content-type", content_type)
+        }
     }
 }
 
-fn handle_get(stream: &mut TcpStream, root_folder: &str, path: &str) {
-    let file_path = format!("{}/{}", root_folder, path);
-    println!("Handling GET request for: {}", file_path);
+// Checked before the filesystem, so old paths redirect even once their
+// files are gone
+fn redirect_for(config: &Config, path: &str) -> Option<Response> {
+    config.redirects.iter().find_map(|rule| {
+        let location = match rule.from.strip_suffix('*') {
+            Some(prefix) => {
+                let splat = path.strip_prefix(prefix)?;
+                rule.to.replace(":splat", splat)
+            }
+            None if rule.from == path => rule.to.clone(),
+            None => return None,
+        };
+        println!("Redirecting /{} to {}", path, location);
+        Some(Response::new(rule.status_code, rule.status_text, Vec::new()).with_header("Location", &location))
+    })
+}
+
+fn bypasses_maintenance(maintenance: &Maintenance, request: &Request) -> bool {
+    let address = request.client_address.ip();
+    maintenance
+        .allowed_paths
+        .iter()
+        .any(|pattern| path_pattern_matches(pattern, &request.path))
+        || in_networks(&maintenance.bypass_networks, address)
+}
+
+// What the target itself supports, matching how handle_request routes it:
+// scripts take POST unless --no-cgi and are never read, files and /__stats
+// are read, and directories answer nothing but OPTIONS
+fn allowed_methods_for(config: &Config, path: &str) -> Result<&'static str, (u16, &'static str)> {
+    if path.starts_with("scripts/") {
+        Ok(if config.cgi { SCRIPT_METHODS } else { "OPTIONS" })
+    } else if path == STATS_PATH && config.hit_counts.is_some() {
+        Ok(FILE_METHODS)
+    } else {
+        let file_path = resolve_path(config, path)?;
+        Ok(if file_path.is_dir() { "OPTIONS" } else { FILE_METHODS })
+    }
+}
+
+fn handle_options(config: &Config, request: &Request) -> Response {
+    match allowed_methods_for(config, &request.path) {
+        Ok(allow) => Response::new(204, "No Content", Vec::new()).with_header("Allow", allow),
+        Err((status_code, status_text)) => error_response(status_code, status_text),
+    }
+}
+
+// A target that doesn't exist is answered as a file would be, so the method
+// is refused before anything is looked up
+fn method_not_allowed(config: &Config, request: &Request) -> Response {
+    let allow = allowed_methods_for(config, &request.path).unwrap_or(FILE_METHODS);
+    error_response(405, "Method Not Allowed").with_header("Allow", allow)
+}
+
+// Runs the handler on its own thread so a stalled file read or script can't
+// hold the connection forever. The worker is left to finish in the background.
+fn handle_request_with_timeout(config: &Arc<Config>, timeout: Duration, request: &Request) -> Response {
+    let (sender, receiver) = mpsc::channel();
+    let worker_config = Arc::clone(config);
+    let worker_request = request.clone();
+    thread::spawn(move || {
+        let response = handle_request(&worker_config, &worker_request);
+        let _ = sender.send(response);
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(response) => response,
+        Err(RecvTimeoutError::Timeout) => {
+            eprintln!(
+                "Request timed out after {}s: {} {}",
+                timeout.as_secs(),
+                request.method,
+                request.path
+            );
+            let detail = format!("Request not handled within {}s", timeout.as_secs());
+            error_response_with_detail(config, &request.raw, 503, "Service Unavailable", &detail)
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            eprintln!("Request handler failed: {} {}", request.method, request.path);
+            error_response_with_detail(config, &request.raw, 500, "Internal Server Error", "Request handler panicked")
+        }
+    }
+}
+
+fn has_encoded_separator(path: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+    path.contains("%2f") || path.contains("%5c")
+}
+
+// Drops empty and "." segments, keeping a trailing slash; None if any
+// segment is ".."
+fn normalize_path(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            _ => segments.push(segment),
+        }
+    }
+    let mut normalized = segments.join("/");
+    if !normalized.is_empty() && (path.ends_with('/') || path.ends_with("/.")) {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+// Both arguments come without leading slashes; returns the remainder of
+// path below base_path
+fn strip_base_path<'a>(path: &'a str, base_path: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(base_path)?;
+    if rest.is_empty() {
+        Some(rest)
+    } else {
+        rest.strip_prefix('/')
+    }
+}
+
+// Decodes %XX escapes, returning None for truncated escapes or when the
+// result is not valid UTF-8
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = path.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Maps a request path onto the root folder. Symlinks are refused unless
+// --follow-symlinks is set, and even then the resolved target must stay
+// inside the root.
+fn resolve_path(config: &Config, path: &str) -> Result<PathBuf, (u16, &'static str)> {
+    let mut file_path = config.root_folder.clone();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        if config.case_insensitive && !file_path.join(component).exists() {
+            match find_case_insensitive(&file_path, component) {
+                Some(entry) => file_path.push(entry),
+                None => return Err((404, "Not Found")),
+            }
+        } else {
+            file_path.push(component);
+        }
+        if !config.follow_symlinks {
+            match fs::symlink_metadata(&file_path) {
+                Ok(metadata) if !metadata.file_type().is_symlink() => {}
+                _ => return Err((404, "Not Found")),
+            }
+        } else if points_to_ancestor(&file_path) {
+            // "up -> ." would make /up/up/up/... an endless copy of the tree
+            println!("Refusing symlink to an ancestor: {}", file_path.display());
+            return Err((404, "Not Found"));
+        }
+    }
+
+    let resolved = fs::canonicalize(&file_path).map_err(|_| (404, "Not Found"))?;
+    if !resolved.starts_with(&config.root_folder) {
+        println!("Path escapes root folder: {}", resolved.display());
+        return Err((403, "Forbidden"));
+    }
+    // Checked on both the requested and the resolved path so a symlink
+    // can't be used to reach an excluded file
+    if is_excluded(config, &file_path) || is_excluded(config, &resolved) {
+        println!("Excluded path: {}", path);
+        return Err((404, "Not Found"));
+    }
+    if is_control_file(config, &resolved) {
+        return Err((404, "Not Found"));
+    }
+    Ok(resolved)
+}
+
+// A symlink whose target contains the link itself. Links that loop back on
+// themselves fail to resolve and are answered 404 by resolve_path anyway.
+fn points_to_ancestor(link: &Path) -> bool {
+    let is_symlink = fs::symlink_metadata(link).is_ok_and(|metadata| metadata.file_type().is_symlink());
+    if !is_symlink {
+        return false;
+    }
+    let (Ok(target), Some(Ok(folder))) = (fs::canonicalize(link), link.parent().map(fs::canonicalize)) else {
+        return false;
+    };
+    folder.starts_with(target)
+}
+
+// _headers and _redirects configure the server and are never served
+fn is_control_file(config: &Config, file_path: &Path) -> bool {
+    [HEADERS_FILE, REDIRECTS_FILE]
+        .iter()
+        .any(|name| file_path == config.root_folder.join(name))
+}
+
+// Patterns containing a slash match the whole path relative to the root;
+// others match any single component, so "*.tmp" or "node_modules" hide
+// entries at every depth
+fn is_excluded(config: &Config, file_path: &Path) -> bool {
+    let relative = match file_path.strip_prefix(&config.root_folder) {
+        Ok(relative) => relative.to_string_lossy(),
+        Err(_) => return false,
+    };
+    config.exclude.iter().any(|pattern| {
+        if pattern.contains('/') {
+            glob_match(pattern.as_bytes(), relative.as_bytes())
+        } else {
+            relative
+                .split('/')
+                .any(|component| glob_match(pattern.as_bytes(), component.as_bytes()))
+        }
+    })
+}
+
+// Shell-style matching: '*' is any run of characters except '/', '?' any one
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], text)
+                || (!text.is_empty() && text[0] != b'/' && glob_match(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(&c)) if c != b'/' => glob_match(&pattern[1..], &text[1..]),
+        (Some(p), Some(c)) if p == c => glob_match(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+// Looks for the one entry of dir whose name matches component ignoring
+// case. Several candidates differing only by case are ambiguous and match
+// nothing.
+fn find_case_insensitive(dir: &Path, component: &str) -> Option<std::ffi::OsString> {
+    let mut candidates = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name())
+        .filter(|name| name.to_string_lossy().to_lowercase() == component.to_lowercase());
+    let candidate = candidates.next()?;
+    if candidates.next().is_some() {
+        println!("Ambiguous case-insensitive match for {} in {}", component, dir.display());
+        return None;
+    }
+    Some(candidate)
+}
+
+fn handle_get(config: &Config, request: &Request) -> Response {
+    let path = request.path.as_str();
+    println!("Handling GET request for: {}", path);
+
+    let file_path = match resolve_path(config, path) {
+        Ok(file_path) => file_path,
+        Err((status_code, status_text)) => {
+            println!("File not found: {}", path);
+            return error_response(status_code, status_text);
+        }
+    };
+
+    // normalize_path keeps the trailing slash, which only a folder can have
+    if path.ends_with('/') && !file_path.is_dir() {
+        println!("File not found: {} is not a directory", path);
+        return error_response(404, "Not Found");
+    }
+
+    if file_path.is_dir() {
+        println!("Forbidden: {} is a directory", file_path.display());
+        return error_response(403, "Forbidden");
+    }
+    // Policies and _headers rules follow the file that is served, so
+    // another spelling of the path or a symlink to it can't get around them
+    let relative = file_path
+        .strip_prefix(&config.root_folder)
+        .map(|relative| relative.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    if is_hotlinked(config, request, &file_path) {
+        println!("Hotlink refused: {}", path);
+        return error_response(403, "Forbidden");
+    }
+
+    // Held while the file is open
+    let _file_slot = match &config.file_slots {
+        Some(file_slots) => match file_slots.acquire(Duration::from_secs(FILE_SLOT_WAIT_SECS)) {
+            Some(slot) => Some(slot),
+            None => {
+                println!("Too many open files, rejecting: {}", path);
+                let detail = format!("No file slot freed up within {}s", FILE_SLOT_WAIT_SECS);
+                return error_response_with_detail(config, &request.raw, 503, "Service Unavailable", &detail)
+                    .with_header("Retry-After", RETRY_AFTER_SECS);
+            }
+        },
+        None => None,
+    };
+
+    let forced_type = match query_param(&request.query, "content-type") {
+        Some(value) if config.allow_content_type_override => {
+            match percent_decode(value).filter(|media_type| is_media_type(media_type)) {
+                Some(media_type) => Some(media_type),
+                None => {
+                    println!("Invalid content-type override: {}", value);
+                    return error_response(400, "Bad Request");
+                }
+            }
+        }
+        _ => None,
+    };
+
+    // With --allow-tail, ?tail= swaps the body for the end of the file; the
+    // headers below are the same either way
+    let tail = query_param(&request.query, "tail").filter(|_| config.allow_tail);
+    let (contents, tail_range) = match tail {
+        Some(tail) => match read_tail(&file_path, tail) {
+            Ok((contents, start, total)) => (contents, Some((start, total))),
+            Err((status_code, status_text)) => return error_response(status_code, status_text),
+        },
+        None => match fs::read(&file_path) {
+            Ok(contents) => (contents, None),
+            Err(e) => {
+                println!("Forbidden: Cannot read file: {}, error: {}", file_path.display(), e);
+                return error_response(403, "Forbidden");
+            }
+        },
+    };
+
+    let mut content_type = get_content_type(&file_path.to_string_lossy());
+    let mut sniffed = false;
+    // The end of a file says nothing about its type
+    if config.sniff && forced_type.is_none() && tail_range.is_none() && content_type == DEFAULT_CONTENT_TYPE {
+        if let Some(sniffed_type) = sniff_content_type(&contents) {
+            content_type = sniffed_type;
+            sniffed = true;
+        }
+    }
+    // Where SVGs aren't trusted inline, an override must not turn a file
+    // into something a browser would run scripts from
+    let folder_policy = svg_policy_for(config, &relative);
+    if folder_policy != SvgPolicy::Inline && forced_type.as_deref().is_some_and(is_active_media_type) {
+        println!("Refusing active content-type override under SVG policy: {}", path);
+        return error_response(403, "Forbidden");
+    }
+    let mut content_type = forced_type.as_deref().unwrap_or(content_type);
+    // Decided by the extension, so the policy holds whatever type is sent
+    let is_svg = file_path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("svg"));
+    let svg_policy = if is_svg { folder_policy } else { SvgPolicy::Inline };
+    if svg_policy == SvgPolicy::Text {
+        content_type = "text/plain; charset=utf-8";
+    }
+    let mut response = Response::new(200, "OK", contents).with_header("Content-type", content_type);
+    match svg_policy {
+        // Scripts and external loads are blocked, inline styles
+        // still render
+        SvgPolicy::Csp => {
+            response = response.with_header(
+                "Content-Security-Policy",
+                "default-src 'none'; style-src 'unsafe-inline'; sandbox",
+            )
+        }
+        SvgPolicy::Attachment => response = response.with_header("Content-Disposition", "attachment"),
+        SvgPolicy::Inline | SvgPolicy::Text => {}
+    }
+    // The ETag describes the whole file, which a hash of the tail doesn't
+    let etag = match tail_range {
+        Some(_) if config.etag_mode == EtagMode::Hash => None,
+        _ => etag_for(config.etag_mode, &file_path, response.body.as_slice()),
+    };
+    // A tail is asked for fresh; revalidation only covers the whole file
+    let not_modified =
+        tail_range.is_none() && etag.as_deref().is_some_and(|etag| if_none_match(request, etag));
+    if let Some(etag) = &etag {
+        response = response.with_header("ETag", etag);
+    }
+    if let Some(cache_control) = cache_control_for(config, &file_path) {
+        response = response.with_header("Cache-Control", cache_control);
+    }
+    // Stop browsers from second-guessing a type we already guessed
+    // or that the client asked for
+    let chosen = sniffed || forced_type.is_some() || svg_policy == SvgPolicy::Text;
+    if chosen && content_type.starts_with("text/") {
+        response = response.with_header("X-Content-Type-Options", "nosniff");
+    }
+    // A HEAD probe or a revalidated copy isn't a download
+    let download = request.method == "GET" && !not_modified;
+    if let Some(hit_counts) = config.hit_counts.as_ref().filter(|_| download) {
+        let count = record_hit(config, hit_counts, &file_path);
+        response = response.with_header("X-Hit-Count", &count.to_string());
+    }
+    // Later rules can't replace what an earlier rule or the
+    // server already set
+    for (pattern, headers) in &config.path_headers {
+        if path_pattern_matches(pattern, &relative) {
+            for (name, value) in headers {
+                if !response.has_header(name) {
+                    response = response.with_header(name, value);
+                }
+            }
+        }
+    }
+    if not_modified {
+        println!("Not modified: {}", path);
+        return Response {
+            status_code: 304,
+            status_text: "Not Modified",
+            body: Vec::new(),
+            ..response
+        };
+    }
+    // An empty tail stays a plain 200, there being no range to describe
+    if let Some((start, total)) = tail_range.filter(|_| !response.body.is_empty()) {
+        let content_range = format!("bytes {}-{}/{}", start, start + response.body.len() as u64 - 1, total);
+        response = Response {
+            status_code: 206,
+            status_text: "Partial Content",
+            ..response
+        }
+        .with_header("Content-Range", &content_range);
+    }
+    response
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Reads the end of a file for ?tail=N (bytes) or ?tail=Nl (lines), along
+// with where it starts and the file's full length
+fn read_tail(file_path: &Path, tail: &str) -> Result<(Vec<u8>, u64, u64), (u16, &'static str)> {
+    let (count, in_lines) = match tail.strip_suffix('l') {
+        Some(lines) => (lines, true),
+        None => (tail, false),
+    };
+    let count = count.parse::<u64>().map_err(|_| (400, "Bad Request"))?;
 
-    if !Path::new(&file_path).exists() {
-        println!("File not found: {}", file_path);
-        respond_with_error(stream, 404, "Not Found");
-        return;
-    }
+    File::open(file_path)
+        .and_then(|mut file| {
+            let total = file.metadata()?.len();
+            let start = if in_lines {
+                tail_lines_start(&mut file, total, count)?
+            } else {
+                total.saturating_sub(count)
+            };
+            let mut contents = Vec::new();
+            file.seek(SeekFrom::Start(start))?;
+            file.read_to_end(&mut contents)?;
+            Ok((contents, start, total))
+        })
+        .map_err(|e| {
+            println!("Forbidden: Cannot read file: {}, error: {}", file_path.display(), e);
+            (403, "Forbidden")
+        })
+}
 
-    match fs::read(&file_path) {
-        Ok(contents) => {
-            let content_type = get_content_type(&file_path);
-            let response = format!(
-                "HTTP/1.1 200 OK\r\nContent-type: {}\r\nConnection: close\r\n\r\n",
-                content_type
-            );
-            if let Err(e) = stream.write(response.as_bytes()) {
-                eprintln!("Failed to write response header: {}", e);
-            }
-            if let Err(e) = stream.write(&contents) {
-                eprintln!("Failed to write response body: {}", e);
+// Scans backwards from the end for the offset where the last `lines` lines
+// begin. A newline ending the file closes the last line rather than
+// starting an empty one.
+fn tail_lines_start(file: &mut File, total: u64, lines: u64) -> std::io::Result<u64> {
+    if lines == 0 {
+        return Ok(total);
+    }
+    let mut chunk = [0; 8192];
+    let mut position = total;
+    let mut newlines = 0;
+    while position > 0 {
+        let size = chunk.len().min(position as usize);
+        position -= size as u64;
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut chunk[..size])?;
+        for i in (0..size).rev() {
+            let offset = position + i as u64;
+            if chunk[i] == b'\n' && offset != total - 1 {
+                newlines += 1;
+                if newlines == lines {
+                    return Ok(offset + 1);
+                }
             }
         }
-        Err(e) => {
-            println!("Forbidden: Cannot read file: {}, error: {}", file_path, e);
-            respond_with_error(stream, 403, "Forbidden");
-        }
     }
+    Ok(0)
 }
 
-fn handle_post(stream: &mut TcpStream, root_folder: &str, path: &str, request: &str) {
-    let script_path = format!("{}/{}", root_folder, path);
-    println!("Handling POST request for: {}", script_path);
+fn handle_post(config: &Config, request: &Request) -> Response {
+    let path = request.path.as_str();
+    println!("Handling POST request for: {}", path);
 
+    // Only scripts take POST; anything else that exists gets the same 405
+    // as any other method it doesn't support
     if !path.starts_with("scripts/") {
-        println!("Script not found: {}", script_path);
-        respond_with_error(stream, 404, "Not Found");
-        return;
+        return match allowed_methods_for(config, path) {
+            Ok(allow) => error_response(405, "Method Not Allowed").with_header("Allow", allow),
+            Err((status_code, status_text)) => {
+                println!("Script not found: {}", path);
+                error_response(status_code, status_text)
+            }
+        };
     }
 
-    if !Path::new(&script_path).exists() {
-        println!("Script not found: {}", script_path);
-        respond_with_error(stream, 404, "Not Found");
-        return;
+    if !config.cgi {
+        println!("Script execution is disabled: {}", path);
+        return method_not_allowed(config, request);
     }
 
+    let script_path = match resolve_path(config, path) {
+        Ok(script_path) => script_path,
+        Err((status_code, status_text)) => {
+            println!("Script not found: {}", path);
+            return error_response(status_code, status_text);
+        }
+    };
+
     // preparer variable
+    let remote_addr = request.client_address.ip().to_string();
     let mut env_vars = vec![
         ("Method", "POST"),
         ("Path", path),
     ];
-    for line in request.lines().skip(1) {
+    for line in request.raw.lines().skip(1) {
         if let Some((key, value)) = line.split_once(':') {
             env_vars.push((key.trim(), value.trim()));
         }
     }
+    // Set last so a client header can't override them
+    env_vars.push(("QUERY_STRING", request.query.as_str()));
+    env_vars.push(("REMOTE_ADDR", remote_addr.as_str()));
+    if let Some(id) = &request.id {
+        env_vars.push(("REQUEST_ID", id.as_str()));
+    }
 
     // executer le script
     match Command::new(&script_path)
@@ -142,41 +2143,186 @@ fn handle_post(stream: &mut TcpStream, root_folder: &str, path: &str, request: &
     {
         Ok(output) => {
             if output.status.success() {
-                let response = format!(
-                    "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{}",
-                    String::from_utf8_lossy(&output.stdout)
-                );
-                if let Err(e) = stream.write(response.as_bytes()) {
-                    eprintln!("Failed to write response: {}", e);
-                }
+                Response::new(200, "OK", output.stdout)
             } else {
-                let response = format!(
-                    "HTTP/1.1 500 Internal Server Error\r\nConnection: close\r\n\r\n{}",
-                    String::from_utf8_lossy(&output.stderr)
-                );
-                if let Err(e) = stream.write(response.as_bytes()) {
-                    eprintln!("Failed to write error response: {}", e);
-                }
+                Response::new(500, "Internal Server Error", output.stderr)
             }
         }
         Err(e) => {
-            eprintln!("Failed to execute script: {}, error: {}", script_path, e);
-            respond_with_error(stream, 500, "Internal Server Error");
+            eprintln!("Failed to execute script: {}, error: {}", script_path.display(), e);
+            let detail = format!("Cannot execute {}: {}", path, e);
+            match e.kind() {
+                ErrorKind::NotFound if !script_path.exists() => error_response(404, "Not Found"),
+                ErrorKind::PermissionDenied => error_response(403, "Forbidden"),
+                // The script exists but can't be run: not an executable
+                // (ENOEXEC) or its #! interpreter is missing
+                ErrorKind::NotFound => error_response_with_detail(config, &request.raw, 502, "Bad Gateway", &detail),
+                _ if e.raw_os_error() == Some(ENOEXEC) => {
+                    error_response_with_detail(config, &request.raw, 502, "Bad Gateway", &detail)
+                }
+                _ => error_response_with_detail(config, &request.raw, 500, "Internal Server Error", &detail),
+            }
+        }
+    }
+}
+
+struct Response {
+    status_code: u16,
+    status_text: &'static str,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status_code: u16, status_text: &'static str, body: Vec<u8>) -> Response {
+        Response {
+            status_code,
+            status_text,
+            headers: Vec::new(),
+            body,
         }
     }
+
+    fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|(existing, _)| existing.eq_ignore_ascii_case(name))
+    }
+}
+
+fn error_response(status_code: u16, status_text: &'static str) -> Response {
+    Response::new(status_code, status_text, Vec::new())
+}
+
+// The detail names files and OS errors, so it only reaches the client
+// with --verbose-errors. Clients that ask for JSON get it as a "detail"
+// field, since with_json_error_body leaves a body it finds alone.
+fn error_response_with_detail(
+    config: &Config,
+    request: &str,
+    status_code: u16,
+    status_text: &'static str,
+    detail: &str,
+) -> Response {
+    if !config.verbose_errors {
+        return error_response(status_code, status_text);
+    }
+    if accepts_json(request) {
+        let body = format!(
+            "{{\"error\": \"{}\", \"status\": {}, \"detail\": \"{}\"}}",
+            json_escape(status_text),
+            status_code,
+            json_escape(detail)
+        );
+        return Response::new(status_code, status_text, body.into_bytes())
+            .with_header("Content-type", "application/json");
+    }
+    Response::new(status_code, status_text, format!("{}\n", detail).into_bytes())
+        .with_header("Content-type", "text/plain; charset=utf-8")
 }
 
-fn respond_with_error(stream: &mut TcpStream, status_code: u16, status_text: &str) {
-    let response = format!(
-        "HTTP/1.1 {} {}\r\nConnection: close\r\n\r\n",
-        status_code, status_text
+// Every response goes out through here so the Date, Content-Length and
+// Connection headers are always present. Answers to HEAD leave out the body
+// but keep the Content-Length it would have had.
+fn send_response(stream: &mut TcpStream, config: &Config, response: &Response, include_body: bool) {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nDate: {}\r\n",
+        response.status_code,
+        response.status_text,
+        http_date(SystemTime::now())
     );
-    if let Err(e) = stream.write(response.as_bytes()) {
-        eprintln!("Failed to write error response: {}", e);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    // --header values fill in whatever the handler didn't set itself
+    for (name, value) in &config.extra_headers {
+        if !response.has_header(name) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    // A 204 must not carry one, and a 304's would describe the full
+    // representation, so both leave it out rather than send 0
+    if response.status_code != 204 && response.status_code != 304 {
+        head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+
+    if let Err(e) = stream.write_all(head.as_bytes()) {
+        eprintln!("Failed to write response header: {}", e);
+        return;
+    }
+    if !include_body {
+        return;
+    }
+    if let Err(e) = stream.write_all(&response.body) {
+        eprintln!("Failed to write response body: {}", e);
     }
 }
 
-fn get_content_type(file_path: &str) -> &str {
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Formats a time as an RFC 7231 IMF-fixdate: "Sun, 06 Nov 1994 08:49:37 GMT"
+fn http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        // 1970-01-01 was a Thursday
+        WEEKDAYS[((days + 3) % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+// Formats a time the way common log format does: "[10/Oct/2000:13:55:36 +0000]"
+fn log_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!(
+        "[{:02}/{}/{}:{:02}:{:02}:{:02} +0000]",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+// Converts days since the Unix epoch to a (year, month, day) civil date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn get_content_type(file_path: &str) -> &'static str {
     if file_path.ends_with(".html") {
         "text/html; charset=utf-8"
     } else if file_path.ends_with(".css") {
@@ -187,9 +2333,890 @@ fn get_content_type(file_path: &str) -> &str {
         "image/png"
     } else if file_path.ends_with(".jpg") || file_path.ends_with(".jpeg") {
         "image/jpeg"
+    } else if file_path.ends_with(".svg") {
+        "image/svg+xml"
     } else if file_path.ends_with(".zip") {
         "application/zip"
     } else {
-        "application/octet-stream"
+        DEFAULT_CONTENT_TYPE
+    }
+}
+
+// Counts are keyed by the path relative to the root, so different spellings
+// of the same URL share one counter
+fn record_hit(config: &Config, hit_counts: &Mutex<HashMap<String, u64>>, file_path: &Path) -> u64 {
+    let key = file_path
+        .strip_prefix(&config.root_folder)
+        .unwrap_or(file_path)
+        .to_string_lossy()
+        .into_owned();
+    let mut hit_counts = hit_counts.lock().unwrap_or_else(|e| e.into_inner());
+    let count = hit_counts.entry(key).or_insert(0);
+    *count += 1;
+    *count
+}
+
+fn stats_response(config: &Config) -> Response {
+    let hit_counts = match &config.hit_counts {
+        Some(hit_counts) => hit_counts.lock().unwrap_or_else(|e| e.into_inner()),
+        None => return error_response(404, "Not Found"),
+    };
+    let mut paths: Vec<_> = hit_counts.iter().collect();
+    paths.sort();
+    let entries: Vec<String> = paths
+        .iter()
+        .map(|(path, count)| format!("\"{}\": {}", json_escape(path), count))
+        .collect();
+    let body = format!("{{{}}}\n", entries.join(", "));
+    Response::new(200, "OK", body.into_bytes()).with_header("Content-type", "application/json")
+}
+
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Counting semaphore bounding how many files handle_get has open at once
+struct FileSlots {
+    available: Mutex<usize>,
+    freed: Condvar,
+}
+
+struct FileSlot<'a>(&'a FileSlots);
+
+impl FileSlots {
+    fn new(limit: usize) -> FileSlots {
+        FileSlots {
+            available: Mutex::new(limit),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self, wait: Duration) -> Option<FileSlot<'_>> {
+        let available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut available, _) = self
+            .freed
+            .wait_timeout_while(available, wait, |available| *available == 0)
+            .unwrap_or_else(|e| e.into_inner());
+        if *available == 0 {
+            return None;
+        }
+        *available -= 1;
+        Some(FileSlot(self))
+    }
+}
+
+impl Drop for FileSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.0.freed.notify_one();
+    }
+}
+
+// Half of the soft RLIMIT_NOFILE, leaving the rest for sockets and scripts.
+// Read from /proc since std has no getrlimit.
+fn default_max_open_files() -> usize {
+    fs::read_to_string("/proc/self/limits")
+        .ok()
+        .and_then(|limits| {
+            limits
+                .lines()
+                .find(|line| line.starts_with("Max open files"))
+                .and_then(|line| line.split_whitespace().nth(3))
+                .and_then(|soft_limit| soft_limit.parse::<usize>().ok())
+        })
+        .map(|soft_limit| (soft_limit / 2).max(1))
+        .unwrap_or(DEFAULT_MAX_OPEN_FILES)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum EtagMode {
+    // Weak tag from size and mtime: stable across restarts and identical
+    // deploys, but blind to same-second edits that keep the size
+    MtimeSize,
+    // Weak tag that also includes the inode, so replacing a file always
+    // changes it, even when a deploy rewrites identical content
+    Inode,
+    // Strong tag from an FNV-1a hash of the content: exact, but hashes the
+    // whole file on every request
+    Hash,
+}
+
+// A protected file may be embedded by pages of this server, by the
+// --allow-referer hosts, and, unless --hotlink-deny-empty, by clients that
+// send no Referer at all
+fn is_hotlinked(config: &Config, request: &Request, file_path: &Path) -> bool {
+    let protected = file_path.extension().is_some_and(|extension| {
+        let extension = extension.to_string_lossy().to_ascii_lowercase();
+        config.hotlink_extensions.contains(&extension)
+    });
+    if !protected {
+        return false;
+    }
+    let referer = match header_value(&request.raw, "Referer") {
+        Some(referer) if !referer.is_empty() => referer,
+        _ => return !config.hotlink_allow_empty,
+    };
+    // scheme://authority/path, where the authority may carry a port
+    let authority = referer
+        .split_once("://")
+        .map_or("", |(_, rest)| rest)
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let host = match authority.rsplit_once(':') {
+        Some((host, port))
+            if port.chars().all(|c| c.is_ascii_digit()) && (!host.contains(':') || host.ends_with(']')) =>
+        {
+            host
+        }
+        _ => authority.as_str(),
+    };
+    let same_site = header_value(&request.raw, "Host").is_some_and(|own| own.eq_ignore_ascii_case(&authority));
+    let allowed = config.allowed_referers.iter().any(|pattern| {
+        glob_match(pattern.as_bytes(), authority.as_bytes()) || glob_match(pattern.as_bytes(), host.as_bytes())
+    });
+    !(same_site || allowed)
+}
+
+// For SVGs, which can carry scripts that run when the file is opened
+// directly from a folder of untrusted uploads
+#[derive(Clone, Copy, PartialEq)]
+enum SvgPolicy {
+    // Served as image/svg+xml with nothing added
+    Inline,
+    // Served as image/svg+xml with a Content-Security-Policy that stops scripts
+    Csp,
+    // Sent with Content-Disposition: attachment so it is downloaded
+    Attachment,
+    // Served as text/plain with nosniff, showing the markup
+    Text,
+}
+
+impl SvgPolicy {
+    fn name(self) -> &'static str {
+        match self {
+            SvgPolicy::Inline => "inline",
+            SvgPolicy::Csp => "csp",
+            SvgPolicy::Attachment => "attachment",
+            SvgPolicy::Text => "text",
+        }
+    }
+}
+
+// Types a browser renders as a document, scripts included
+fn is_active_media_type(media_type: &str) -> bool {
+    let essence = media_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    essence == "text/html" || essence == "text/xml" || essence == "application/xml" || essence.ends_with("+xml")
+}
+
+fn svg_policy_for(config: &Config, path: &str) -> SvgPolicy {
+    config
+        .svg_policies
+        .iter()
+        .filter(|(folder, _)| {
+            folder.is_empty() || path.strip_prefix(folder.as_str()).is_some_and(|rest| rest.starts_with('/'))
+        })
+        .max_by_key(|(folder, _)| folder.len())
+        .map(|(_, policy)| *policy)
+        .unwrap_or(SvgPolicy::Inline)
+}
+
+fn etag_for(mode: EtagMode, file_path: &Path, contents: &[u8]) -> Option<String> {
+    if mode == EtagMode::Hash {
+        return Some(format!("\"{:016x}\"", fnv1a(contents)));
+    }
+    let metadata = fs::metadata(file_path).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    match mode {
+        EtagMode::Inode => Some(format!("W/\"{:x}-{:x}-{:x}\"", metadata.ino(), metadata.len(), mtime)),
+        _ => Some(format!("W/\"{:x}-{:x}\"", metadata.len(), mtime)),
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Weak comparison, as RFC 7232 requires for If-None-Match: W/"x" and "x"
+// are the same tag. Strong comparison would only matter for If-Range, and
+// ranges here are limited to ?tail=, which carries no validator.
+fn if_none_match(request: &Request, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    header_value(&request.raw, "If-None-Match").is_some_and(|value| {
+        value.trim() == "*" || value.split(',').any(|candidate| opaque(candidate) == opaque(etag))
+    })
+}
+
+// The first --cache-rule listing the file's extension wins; a rule for "*"
+// covers everything else
+fn cache_control_for<'a>(config: &'a Config, file_path: &Path) -> Option<&'a str> {
+    let extension = file_path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    let matching = |wanted: &str| {
+        config
+            .cache_rules
+            .iter()
+            .find(|(extensions, _)| extensions.iter().any(|e| e == wanted))
+            .map(|(_, value)| value.as_str())
+    };
+    extension
+        .and_then(|extension| matching(&extension))
+        .or_else(|| matching("*"))
+}
+
+// Guesses a type from well-known magic numbers, for files whose extension
+// told us nothing
+fn sniff_content_type(contents: &[u8]) -> Option<&'static str> {
+    let head = &contents[..contents.len().min(512)];
+    if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some("image/png");
+    }
+    if head.starts_with(&[0xff, 0xd8, 0xff]) {
+        return Some("image/jpeg");
+    }
+    if head.starts_with(b"%PDF-") {
+        return Some("application/pdf");
+    }
+    if head.starts_with(&[0x1f, 0x8b]) {
+        return Some("application/gzip");
+    }
+
+    let text = String::from_utf8_lossy(head).trim_start().to_ascii_lowercase();
+    if text.starts_with("<!doctype html") || text.starts_with("<html") {
+        return Some("text/html; charset=utf-8");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static NEXT_ROOT: AtomicUsize = AtomicUsize::new(0);
+
+    // A fresh, empty root folder under the system temp directory, removed
+    // again when the test is done with it
+    struct TempRoot(PathBuf);
+
+    impl std::ops::Deref for TempRoot {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempRoot {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn temp_root() -> TempRoot {
+        let number = NEXT_ROOT.fetch_add(1, Ordering::SeqCst);
+        let root = env::temp_dir().join(format!("rustywebserver-test-{}-{}", process::id(), number));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        TempRoot(root)
+    }
+
+    fn config_for(root: &Path, options: &[&str]) -> Config {
+        let mut args: Vec<String> = options.iter().map(|option| option.to_string()).collect();
+        args.push("8080".to_string());
+        args.push(root.to_string_lossy().into_owned());
+        load_config(&args).unwrap()
+    }
+
+    fn parse(config: &Config, raw: &str) -> Result<Request, Response> {
+        parse_request(config, raw, SocketAddr::from((Ipv4Addr::LOCALHOST, 40000)), None).map_err(|(_, response)| response)
+    }
+
+    // What handle_connection would send, minus the framing headers
+    fn respond(config: &Config, raw: &str) -> Response {
+        match parse(config, raw) {
+            Ok(request) => handle_request(config, &request),
+            Err(response) => response,
+        }
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(existing, _)| existing.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Sends raw over a real socket to handle_connection and returns
+    // everything written back
+    fn exchange(config: Config, raw: &str) -> String {
+        exchange_in_parts(config, &[raw])
+    }
+
+    // Sends each part in its own write, a moment apart
+    fn exchange_in_parts(config: Config, parts: &[&str]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = Arc::new(config);
+        let server = thread::spawn(move || {
+            let (stream, client_address) = listener.accept().unwrap();
+            handle_connection(stream, client_address, &config);
+        });
+        let mut client = TcpStream::connect(address).unwrap();
+        for (i, part) in parts.iter().enumerate() {
+            if i > 0 {
+                thread::sleep(Duration::from_millis(100));
+            }
+            client.write_all(part.as_bytes()).unwrap();
+        }
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        drop(client);
+        server.join().unwrap();
+        reply
+    }
+
+    #[test]
+    fn unknown_method_is_not_implemented() {
+        let root = temp_root();
+        let config = config_for(&root, &[]);
+        let response = respond(&config, "FROB / HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code, 501);
+        assert_eq!(header(&response, "Allow"), None);
+    }
+
+    #[test]
+    fn standard_method_is_not_allowed() {
+        let root = temp_root();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let config = config_for(&root, &[]);
+        for method in ["DELETE", "POST"] {
+            let response = respond(&config, &format!("{} /a.txt HTTP/1.1\r\n\r\n", method));
+            assert_eq!(response.status_code, 405, "{}", method);
+            assert_eq!(header(&response, "Allow"), Some(FILE_METHODS), "{}", method);
+        }
+        assert_eq!(respond(&config, "POST /missing.txt HTTP/1.1\r\n\r\n").status_code, 404);
+    }
+
+    #[test]
+    fn encoded_separators_are_rejected() {
+        let root = temp_root();
+        fs::create_dir(root.join("docs")).unwrap();
+        let config = config_for(&root, &[]);
+        for target in ["/docs%2F..%2F..%2Fetc%2Fpasswd", "/docs%2f..", "/%2e%2e%2Fsecret", "/docs/..%5C..%5Csecret"] {
+            let response = respond(&config, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+            assert_eq!(response.status_code, 400, "{}", target);
+        }
+    }
+
+    #[test]
+    fn root_target_is_the_root_folder() {
+        let root = temp_root();
+        let config = config_for(&root, &[]);
+        let request = parse(&config, "GET / HTTP/1.1\r\n\r\n").ok().unwrap();
+        assert_eq!(request.path, "");
+        assert_eq!(resolve_path(&config, &request.path).unwrap(), config.root_folder);
+        // The root is a directory, and directories aren't served
+        assert_eq!(handle_request(&config, &request).status_code, 403);
+    }
+
+    #[test]
+    fn empty_and_asterisk_targets_are_bad_requests() {
+        let root = temp_root();
+        let config = config_for(&root, &[]);
+        for raw in ["", "\r\n\r\n", "GET\r\n\r\n", "GET  HTTP/1.1\r\n\r\n", "GET * HTTP/1.1\r\n\r\n"] {
+            assert_eq!(respond(&config, raw).status_code, 400, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn http_date_is_an_imf_fixdate() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        // Leap day
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(951_782_400)), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn every_response_carries_the_current_date() {
+        let root = temp_root();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+        for raw in ["GET /a.txt HTTP/1.1\r\n\r\n", "GET /missing HTTP/1.1\r\n\r\n", "BAD\r\n\r\n"] {
+            let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let reply = exchange(config_for(&root, &[]), raw);
+            let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let date = reply
+                .lines()
+                .find_map(|line| line.strip_prefix("Date: "))
+                .unwrap_or_else(|| panic!("no Date header for {:?}", raw));
+            // The header must be the formatting of a moment during the exchange
+            let parsed = (before..=after).find(|&secs| http_date(UNIX_EPOCH + Duration::from_secs(secs)) == date);
+            assert!(parsed.is_some(), "unexpected Date {:?}", date);
+        }
+    }
+
+    #[test]
+    fn control_characters_in_paths_are_rejected() {
+        let root = temp_root();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+        let config = config_for(&root, &[]);
+        let targets = ["/a.txt%00.html", "/a%0a.txt", "/a%0D%0Ab", "/a%1f", "/a%7f", "/a\u{1}.txt", "/a\0.txt", "/a%4", "/a%zz"];
+        for target in targets {
+            let response = respond(&config, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+            assert_eq!(response.status_code, 400, "{:?}", target);
+        }
+        assert_eq!(percent_decode("a%20b%2e").as_deref(), Some("a b."));
+    }
+
+    #[test]
+    fn fragment_never_reaches_the_path() {
+        let root = temp_root();
+        fs::create_dir(root.join("docs")).unwrap();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+        let config = config_for(&root, &[]);
+
+        let request = parse(&config, "GET /docs/?sort=name#top HTTP/1.1\r\n\r\n").ok().unwrap();
+        assert_eq!(request.path, "docs/");
+        assert_eq!(request.query, "sort=name");
+        assert_eq!(resolve_path(&config, &request.path).unwrap(), config.root_folder.join("docs"));
+
+        let request = parse(&config, "GET /a.txt#x?y=1 HTTP/1.1\r\n\r\n").ok().unwrap();
+        assert_eq!(request.path, "a.txt");
+        assert_eq!(request.query, "");
+        assert_eq!(handle_request(&config, &request).status_code, 200);
+
+        // An encoded '#' is part of the name, not a fragment
+        let request = parse(&config, "GET /a%23b HTTP/1.1\r\n\r\n").ok().unwrap();
+        assert_eq!(request.path, "a#b");
+    }
+
+    #[test]
+    fn latest_symlink_inside_root_is_followed() {
+        let root = temp_root();
+        let outside = temp_root();
+        fs::create_dir(root.join("v2.3")).unwrap();
+        fs::write(root.join("v2.3/app.tar"), "release").unwrap();
+        fs::write(outside.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink("v2.3", root.join("latest")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+
+        let config = config_for(&root, &["--follow-symlinks"]);
+        let response = respond(&config, "GET /latest/app.tar HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, b"release");
+        assert_eq!(respond(&config, "GET /escape/secret.txt HTTP/1.1\r\n\r\n").status_code, 403);
+
+        // Without the flag both links are treated as missing
+        let config = config_for(&root, &[]);
+        assert_eq!(respond(&config, "GET /latest/app.tar HTTP/1.1\r\n\r\n").status_code, 404);
+        assert_eq!(respond(&config, "GET /escape/secret.txt HTTP/1.1\r\n\r\n").status_code, 404);
+    }
+
+    #[test]
+    fn tail_responses_keep_the_file_headers() {
+        let root = temp_root();
+        fs::create_dir(root.join("up")).unwrap();
+        fs::write(root.join("up/app.log"), "one\ntwo\nthree\n").unwrap();
+        fs::write(root.join("_headers"), "/up/*\n  Content-Security-Policy: sandbox\n").unwrap();
+        let config = config_for(&root, &["--allow-tail", "--hit-count", "--cache-rule", "log=no-cache"]);
+
+        let whole = respond(&config, "GET /up/app.log HTTP/1.1\r\n\r\n");
+        let tail = respond(&config, "GET /up/app.log?tail=1l HTTP/1.1\r\n\r\n");
+        assert_eq!(tail.status_code, 206);
+        assert_eq!(tail.body, b"three\n");
+        assert_eq!(header(&tail, "Content-Range"), Some("bytes 8-13/14"));
+        for name in ["Content-type", "ETag", "Cache-Control", "Content-Security-Policy"] {
+            assert_eq!(header(&tail, name), header(&whole, name), "{}", name);
+        }
+        assert_eq!(header(&tail, "X-Hit-Count"), Some("2"));
+
+        let bytes = respond(&config, "GET /up/app.log?tail=3 HTTP/1.1\r\n\r\n");
+        assert_eq!(bytes.body, b"ee\n");
+        assert_eq!(respond(&config, "GET /up/app.log?tail=x HTTP/1.1\r\n\r\n").status_code, 400);
+    }
+
+    #[test]
+    fn self_test_skips_files_that_are_never_served() {
+        let root = temp_root();
+        for name in ["_headers", "_redirects", "draft.tmp", "file.txt"] {
+            fs::write(root.join(name), "").unwrap();
+        }
+        fs::create_dir(root.join("sub")).unwrap();
+        let config = config_for(&root, &["--exclude", "*.tmp"]);
+        assert_eq!(servable_root_files(&config).unwrap(), vec!["file.txt".to_string()]);
+        assert_eq!(respond(&config, "GET /_headers HTTP/1.1\r\n\r\n").status_code, 404);
+        assert_eq!(respond(&config, "GET /_redirects HTTP/1.1\r\n\r\n").status_code, 404);
+    }
+
+    #[test]
+    fn svg_policy_follows_the_extension() {
+        let root = temp_root();
+        fs::create_dir(root.join("uploads")).unwrap();
+        fs::write(root.join("uploads/a.svg"), "<svg><script>alert(1)</script></svg>").unwrap();
+        fs::write(root.join("uploads/b.txt"), "<script>alert(1)</script>").unwrap();
+        let config = config_for(
+            &root,
+            &["--svg-policy", "uploads=attachment", "--allow-tail", "--allow-content-type-override"],
+        );
+
+        let tail = respond(&config, "GET /uploads/a.svg?tail=10 HTTP/1.1\r\n\r\n");
+        assert_eq!(tail.status_code, 206);
+        assert_eq!(header(&tail, "Content-Disposition"), Some("attachment"));
+
+        let retyped = respond(&config, "GET /uploads/a.svg?content-type=text/plain HTTP/1.1\r\n\r\n");
+        assert_eq!(header(&retyped, "Content-Disposition"), Some("attachment"));
+
+        for target in [
+            "/uploads/b.txt?content-type=text/html",
+            "/uploads/b.txt?content-type=image/svg%2Bxml",
+            "/uploads/b.txt?content-type=application/xml",
+        ] {
+            let response = respond(&config, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+            assert_eq!(response.status_code, 403, "{}", target);
+        }
+    }
+
+    #[test]
+    fn sloppy_paths_are_normalized() {
+        assert_eq!(normalize_path("//docs///index.html").as_deref(), Some("docs/index.html"));
+        assert_eq!(normalize_path("/./docs/./").as_deref(), Some("docs/"));
+        assert_eq!(normalize_path("/docs/../secret"), None);
+        assert_eq!(strip_base_path("app/docs", "app"), Some("docs"));
+        assert_eq!(strip_base_path("application", "app"), None);
+
+        let root = temp_root();
+        fs::write(root.join("file.txt"), "a").unwrap();
+        let config = config_for(&root, &[]);
+        assert_eq!(respond(&config, "GET //./file.txt HTTP/1.1\r\n\r\n").status_code, 200);
+        assert_eq!(respond(&config, "GET /file.txt/ HTTP/1.1\r\n\r\n").status_code, 404);
+    }
+
+    #[test]
+    fn empty_file_is_sent_with_a_zero_length() {
+        let root = temp_root();
+        fs::write(root.join("empty.txt"), "").unwrap();
+        for method in ["GET", "HEAD"] {
+            let reply = exchange(config_for(&root, &[]), &format!("{} /empty.txt HTTP/1.1\r\n\r\n", method));
+            assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"), "{}", reply);
+            assert!(reply.contains("\r\nContent-Length: 0\r\n"), "{}", reply);
+            assert!(reply.ends_with("\r\n\r\n"), "{}", reply);
+        }
+    }
+
+    #[test]
+    fn head_errors_have_no_body() {
+        let root = temp_root();
+        let config = config_for(&root, &[]);
+        let raw = "HEAD /../a HTTP/1.1\r\nAccept: application/json\r\n\r\n";
+        let error = parse_request(&config, raw, SocketAddr::from((Ipv4Addr::LOCALHOST, 40000)), None);
+        assert!(matches!(error, Err((Some("HEAD"), _))));
+
+        let reply = exchange(config, raw);
+        assert!(reply.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", reply);
+        assert!(reply.ends_with("\r\n\r\n"), "{}", reply);
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let root = temp_root();
+        let config = config_for(&root, &[]);
+        let with = |value: &str| {
+            let raw = format!("GET / HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", value);
+            parse(&config, &raw).ok().unwrap()
+        };
+        assert!(if_none_match(&with("W/\"x\""), "\"x\""));
+        assert!(if_none_match(&with("\"x\""), "W/\"x\""));
+        assert!(if_none_match(&with("\"a\", W/\"x\""), "\"x\""));
+        assert!(if_none_match(&with("*"), "\"x\""));
+        assert!(!if_none_match(&with("\"y\""), "\"x\""));
+        assert!(!if_none_match(&parse(&config, "GET / HTTP/1.1\r\n\r\n").ok().unwrap(), "\"x\""));
+    }
+
+    #[test]
+    fn strong_etag_revalidates_whole_files_only() {
+        let root = temp_root();
+        fs::write(root.join("log.txt"), "one\ntwo\n").unwrap();
+        let config = config_for(&root, &["--etag-mode", "hash", "--allow-tail"]);
+        let first = respond(&config, "GET /log.txt HTTP/1.1\r\n\r\n");
+        let etag = header(&first, "ETag").unwrap().to_string();
+        assert!(!etag.starts_with("W/"));
+
+        let again = respond(&config, &format!("GET /log.txt HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", etag));
+        assert_eq!(again.status_code, 304);
+        let weak = respond(&config, &format!("GET /log.txt HTTP/1.1\r\nIf-None-Match: W/{}\r\n\r\n", etag));
+        assert_eq!(weak.status_code, 304);
+
+        let tail = respond(&config, &format!("GET /log.txt?tail=4 HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", etag));
+        assert_eq!(tail.status_code, 206);
+        assert_eq!(tail.body, b"two\n");
+    }
+
+    #[test]
+    fn max_uri_length_is_capped() {
+        let root = temp_root();
+        let load = |limit: &str| {
+            let args = ["--max-uri-length", limit, "8080", &root.display().to_string()].map(String::from);
+            load_config(&args)
+        };
+        assert!(load("18446744073709551615").is_err());
+        assert!(load("1048577").is_err());
+        assert!(load("1048576").is_ok());
+    }
+
+    #[test]
+    fn zero_max_uri_length_reads_long_request_lines() {
+        let root = temp_root();
+        let target = format!("/{}", "a".repeat(20000));
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        let reply = exchange(config_for(&root, &["--max-uri-length", "0"]), &raw);
+        assert!(reply.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", reply);
+
+        let reply = exchange(config_for(&root, &["--max-uri-length", "100"]), &raw);
+        assert!(reply.starts_with("HTTP/1.1 414 URI Too Long\r\n"), "{}", reply);
+    }
+
+    #[test]
+    fn allow_lists_what_the_target_supports() {
+        let root = temp_root();
+        fs::create_dir(root.join("scripts")).unwrap();
+        fs::write(root.join("scripts/x"), "#!/bin/sh\n").unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        for (options, target, allow) in [
+            (&[][..], "/scripts/x", SCRIPT_METHODS),
+            (&["--no-cgi"][..], "/scripts/x", "OPTIONS"),
+            (&[][..], "/a.txt", FILE_METHODS),
+        ] {
+            let config = config_for(&root, options);
+            // Every method left out of Allow is refused with that same list
+            for method in ["GET", "HEAD", "POST", "PUT", "DELETE"] {
+                let response = respond(&config, &format!("{} {} HTTP/1.1\r\n\r\n", method, target));
+                let listed = allow.split(", ").any(|allowed| allowed == method);
+                assert_eq!(response.status_code == 405, !listed, "{} {} {:?}", method, target, options);
+                if !listed {
+                    assert_eq!(header(&response, "Allow"), Some(allow), "{} {}", method, target);
+                }
+            }
+            let response = respond(&config, &format!("OPTIONS {} HTTP/1.1\r\n\r\n", target));
+            assert_eq!(header(&response, "Allow"), Some(allow), "{}", target);
+        }
+    }
+
+    #[test]
+    fn refused_requests_keep_their_id() {
+        let root = temp_root();
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(9000));
+        for raw in [
+            "GET /../a HTTP/1.1\r\nX-Request-Id: abc\r\n\r\n",
+            "GET / HTTP/2.0\r\nX-Request-Id: abc\r\n\r\n",
+            "GET * HTTP/1.1\r\nX-Request-Id: abc\r\n\r\n",
+        ] {
+            let reply = exchange(config_for(&root, &["--request-id"]), raw);
+            assert!(reply.contains("\r\nX-Request-Id: abc\r\n"), "{}", reply);
+        }
+
+        let reply = exchange(config_for(&root, &["--request-id", "--max-uri-length", "100"]), &long);
+        assert!(reply.starts_with("HTTP/1.1 414 URI Too Long\r\n"), "{}", reply);
+        assert!(reply.contains("\r\nX-Request-Id: "), "{}", reply);
+    }
+
+    #[test]
+    fn svg_policy_follows_the_served_file() {
+        let root = temp_root();
+        fs::create_dir(root.join("uploads")).unwrap();
+        fs::write(root.join("uploads/a.svg"), "<svg><script>alert(1)</script></svg>").unwrap();
+        std::os::unix::fs::symlink("uploads", root.join("latest")).unwrap();
+        let config = config_for(
+            &root,
+            &["--svg-policy", "uploads=attachment", "--case-insensitive", "--follow-symlinks"],
+        );
+        for target in ["/uploads/a.svg", "/UPLOADS/a.svg", "/latest/a.svg"] {
+            let response = respond(&config, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+            assert_eq!(response.status_code, 200, "{}", target);
+            assert_eq!(header(&response, "Content-Disposition"), Some("attachment"), "{}", target);
+        }
+    }
+
+    #[test]
+    fn headers_rules_follow_the_served_file() {
+        let root = temp_root();
+        fs::create_dir(root.join("uploads")).unwrap();
+        fs::write(root.join("uploads/a.svg"), "<svg></svg>").unwrap();
+        fs::write(root.join(HEADERS_FILE), "/uploads/*\n  Content-Security-Policy: sandbox\n").unwrap();
+        std::os::unix::fs::symlink("uploads", root.join("latest")).unwrap();
+        let config = config_for(&root, &["--case-insensitive", "--follow-symlinks"]);
+        for target in ["/uploads/a.svg", "/UPLOADS/a.svg", "/latest/a.svg"] {
+            let response = respond(&config, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+            assert_eq!(header(&response, "Content-Security-Policy"), Some("sandbox"), "{}", target);
+        }
+    }
+
+    #[test]
+    fn scripts_are_never_read() {
+        let root = temp_root();
+        fs::create_dir(root.join("scripts")).unwrap();
+        fs::write(root.join("scripts/s.sh"), "#!/bin/sh\necho secret\n").unwrap();
+        let config = config_for(&root, &[]);
+        for method in ["GET", "HEAD"] {
+            let response = respond(&config, &format!("{} /scripts/s.sh HTTP/1.1\r\n\r\n", method));
+            assert_eq!(response.status_code, 405, "{}", method);
+            assert!(response.body.is_empty(), "{}", method);
+        }
+    }
+
+    #[test]
+    fn verbose_errors_follow_accept() {
+        use std::os::unix::fs::PermissionsExt;
+        let root = temp_root();
+        fs::create_dir(root.join("scripts")).unwrap();
+        fs::write(root.join("scripts/bad"), "not a program").unwrap();
+        fs::set_permissions(root.join("scripts/bad"), fs::Permissions::from_mode(0o755)).unwrap();
+        let config = config_for(&root, &["--verbose-errors"]);
+
+        let plain = respond(&config, "POST /scripts/bad HTTP/1.1\r\n\r\n");
+        assert_eq!(plain.status_code, 502);
+        assert_eq!(header(&plain, "Content-type"), Some("text/plain; charset=utf-8"));
+
+        let json = respond(&config, "POST /scripts/bad HTTP/1.1\r\nAccept: application/json\r\n\r\n");
+        assert_eq!(json.status_code, 502);
+        assert_eq!(header(&json, "Content-type"), Some("application/json"));
+        let body = String::from_utf8(json.body).unwrap();
+        let expected = "{\"error\": \"Bad Gateway\", \"status\": 502, \"detail\": \"Cannot execute scripts/bad: ";
+        assert!(body.starts_with(expected), "{}", body);
+    }
+
+    #[test]
+    fn headers_sent_late_are_read() {
+        let root = temp_root();
+        fs::write(root.join("file.txt"), "a").unwrap();
+        let reply = exchange_in_parts(
+            config_for(&root, &[]),
+            &["DELETE /file.txt HTTP/1.1\r\n", "Accept: application/json\r\n", "\r\n"],
+        );
+        assert!(reply.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", reply);
+        assert!(reply.ends_with("{\"error\": \"Method Not Allowed\", \"status\": 405}"), "{}", reply);
+    }
+
+    #[test]
+    fn framing_headers_are_reserved() {
+        for option in ["Date: x", "content-length: 5", "Transfer-Encoding: chunked", "Connection: keep-alive"] {
+            assert!(parse_header_option(option).is_err(), "{}", option);
+        }
+        assert_eq!(
+            parse_header_option("X-Served-By:  web1 ").ok(),
+            Some(("X-Served-By".to_string(), "web1".to_string()))
+        );
+    }
+
+    #[test]
+    fn symlink_loops_and_ancestor_links_are_not_followed() {
+        let root = temp_root();
+        fs::create_dir(root.join("docs")).unwrap();
+        fs::write(root.join("docs/file.txt"), "a").unwrap();
+        std::os::unix::fs::symlink("loop", root.join("loop")).unwrap();
+        std::os::unix::fs::symlink(".", root.join("docs/up")).unwrap();
+        std::os::unix::fs::symlink("..", root.join("docs/parent")).unwrap();
+        let config = config_for(&root, &["--follow-symlinks"]);
+        for (target, status_code) in [
+            ("/docs/file.txt", 200),
+            ("/loop", 404),
+            ("/docs/up/file.txt", 404),
+            ("/docs/up/up/file.txt", 404),
+            ("/docs/parent/docs/file.txt", 404),
+        ] {
+            let response = respond(&config, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+            assert_eq!(response.status_code, status_code, "{}", target);
+        }
+    }
+
+    #[test]
+    fn admin_networks_match_by_prefix() {
+        let ip = |address: &str| address.parse::<IpAddr>().unwrap();
+        let contains = |network: &str, address: &str| {
+            let (network, prefix_len) = parse_network(network).unwrap();
+            network_contains(network, prefix_len, ip(address))
+        };
+        assert!(contains("0.0.0.0/0", "203.0.113.9"));
+        assert!(contains("10.0.0.0/8", "10.255.1.2"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.5/32", "192.168.1.5"));
+        assert!(!contains("192.168.1.5/32", "192.168.1.6"));
+        assert!(contains("127.0.0.1", "127.0.0.1"));
+        assert!(!contains("127.0.0.1", "127.0.0.2"));
+        assert!(contains("::1/128", "::1"));
+        assert!(!contains("::1/128", "::2"));
+        assert!(contains("2001:db8::/32", "2001:db8:1::1"));
+
+        // An IPv4 network never covers an IPv6 client, even a mapped one
+        assert!(!contains("0.0.0.0/0", "::1"));
+        assert!(!contains("::/0", "127.0.0.1"));
+        assert!(!contains("127.0.0.1", "::ffff:127.0.0.1"));
+
+        for network in ["10.0.0.0/33", "::/129", "10.0.0.0/x", "10.0.0.0/-1", "localhost", "10.0.0/8"] {
+            assert!(parse_network(network).is_err(), "{}", network);
+        }
+    }
+
+    #[test]
+    fn redirects_substitute_the_splat() {
+        let root = temp_root();
+        fs::write(root.join(REDIRECTS_FILE), "/blog/* /posts/:splat\n/old.html /new.html 308\n").unwrap();
+        let config = config_for(&root, &[]);
+        let location = |path: &str| {
+            let response = redirect_for(&config, path)?;
+            Some((response.status_code, header(&response, "Location")?.to_string()))
+        };
+        assert_eq!(location("blog/2024/hello"), Some((301, "/posts/2024/hello".to_string())));
+        assert_eq!(location("blog/"), Some((301, "/posts/".to_string())));
+        assert_eq!(location("old.html"), Some((308, "/new.html".to_string())));
+        assert_eq!(location("old.html.bak"), None);
+        assert_eq!(location("blogger"), None);
+    }
+
+    #[test]
+    fn log_format_errors_are_caught_at_startup() {
+        for format in ["%x", "took %", "%>x", "%{Bad Name}i", "%{Referer}", "%{Referer}o", "%{}i"] {
+            assert!(parse_log_format(format).is_err(), "{}", format);
+        }
+
+        let fields = parse_log_format("%h %>s %{Referer}i 100%%").unwrap();
+        assert!(matches!(
+            fields.as_slice(),
+            [
+                LogField::ClientIp,
+                LogField::Literal(space),
+                LogField::Status,
+                LogField::Literal(_),
+                LogField::Header(name),
+                LogField::Literal(tail),
+            ] if space == " " && name == "Referer" && tail == " 100%"
+        ));
     }
 }